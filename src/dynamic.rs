@@ -0,0 +1,142 @@
+use crate::types::{
+    AddOptions, Dyn, DynEntry, EnvOption, Error, ListResult, Parse, SetTypeDescriptor,
+};
+use crate::{ListIter, Session};
+
+/// Session for the set whose type is only known at runtime, such as the sets defined in
+/// configuration files. The entries are checked against the type of the set in the kernel,
+/// `Error::TypeMismatch` is returned if differs.
+pub struct DynSession {
+    session: Session<Dyn>,
+    descriptor: &'static SetTypeDescriptor,
+    template: DynEntry,
+}
+
+impl DynSession {
+    /// Open the ipset `name`, the type of the set is queried from the kernel.
+    pub fn new(name: String) -> Result<DynSession, Error> {
+        let session = Session::new(name);
+        let typename = session.typename()?;
+        Self::with_session(session, typename)
+    }
+
+    /// Open the ipset `name` with the already known `typename`.
+    pub(crate) fn with_typename(name: String, typename: String) -> Result<DynSession, Error> {
+        Self::with_session(Session::new(name), typename)
+    }
+
+    fn with_session(session: Session<Dyn>, typename: String) -> Result<DynSession, Error> {
+        let descriptor = SetTypeDescriptor::parse(&typename)?;
+        let template = DynEntry::template(descriptor.typename)?;
+        Ok(Self {
+            session,
+            descriptor,
+            template,
+        })
+    }
+
+    /// The typename of the set, like `hash:net,port`.
+    pub fn typename(&self) -> &str {
+        self.descriptor.typename
+    }
+
+    /// The descriptor of the set type.
+    pub fn descriptor(&self) -> &'static SetTypeDescriptor {
+        self.descriptor
+    }
+
+    /// Parse an entry of the set from `s`, like `192.168.0.0/24,80` for `hash:net,port`.
+    pub fn parse(&self, s: &str) -> Result<DynEntry, Error> {
+        let mut entry = self.template.clone();
+        entry.parse(s)?;
+        Ok(entry)
+    }
+
+    /// Check if the data types of `entry` match the type of the set.
+    fn check(&self, entry: DynEntry) -> Result<DynEntry, Error> {
+        if entry.same_type(&self.template) {
+            Ok(entry)
+        } else {
+            Err(Error::TypeMismatch(format!(
+                "{} is not an entry of {}",
+                entry, self.descriptor.typename
+            )))
+        }
+    }
+
+    pub fn set_option(&mut self, option: EnvOption) {
+        self.session.set_option(option)
+    }
+
+    pub fn unset_option(&mut self, option: EnvOption) {
+        self.session.unset_option(option)
+    }
+
+    /// Take the warnings reported by libipset, see `Session::take_warnings`.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        self.session.take_warnings()
+    }
+
+    /// Test if `entry` is in the set.
+    pub fn test(&mut self, entry: impl Into<DynEntry>) -> Result<bool, Error> {
+        let entry = self.check(entry.into())?;
+        self.session.test(entry)
+    }
+
+    /// Add `entry` into the set.
+    pub fn add(
+        &mut self,
+        entry: impl Into<DynEntry>,
+        options: &(impl AddOptions + ?Sized),
+    ) -> Result<bool, Error> {
+        let entry = self.check(entry.into())?;
+        self.session.add(entry, options)
+    }
+
+    /// Delete `entry` from the set.
+    pub fn del(&mut self, entry: impl Into<DynEntry>) -> Result<bool, Error> {
+        let entry = self.check(entry.into())?;
+        self.session.del(entry)
+    }
+
+    /// List all the entries in the set, the type of the listed set is checked too.
+    pub fn list(&mut self) -> Result<ListResult<Dyn>, Error> {
+        let result = self.session.list_with(|| self.template.clone())?;
+        if let ListResult::Normal(result) = &result {
+            if result.typ != self.descriptor.typename {
+                return Err(Error::TypeMismatch(result.typ.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Same as `list`, but the list is decoded from the xml output, see `Session::list_xml`.
+    pub fn list_xml(&mut self) -> Result<ListResult<Dyn>, Error> {
+        let result = self.session.list_xml_with(|| self.template.clone())?;
+        if let ListResult::Normal(result) = &result {
+            if result.typ != self.descriptor.typename {
+                return Err(Error::TypeMismatch(result.typ.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// List the members of the set one by one, see `Session::list_iter`.
+    /// The type of the listed set is checked too.
+    pub fn list_iter(&self) -> ListIter<Dyn, impl Fn() -> DynEntry> {
+        let template = self.template.clone();
+        self.session
+            .list_iter_with(move || template.clone())
+            .with_typename(self.descriptor.typename)
+    }
+
+    /// Clear all the content in the set.
+    pub fn flush(&mut self) -> Result<bool, Error> {
+        self.session.flush()
+    }
+
+    /// Destroy the set.
+    pub fn destroy(&mut self) -> Result<bool, Error> {
+        self.session.destroy()
+    }
+}
@@ -5,7 +5,10 @@
 //! * test
 //! * create
 //! * list
+//! * header
 //! * destroy
+//! * rename
+//! * swap
 //! * flush
 //! * save
 //! * restore
@@ -27,6 +30,7 @@
 //! * HashNetPort
 //! * HashNetPortNet
 //! * ListSet
+//! * Dyn, the type is only known at runtime, see `DynSession`
 //!
 //! # Example
 //! ```rust,no_run
@@ -95,14 +99,24 @@
 //! }
 //! ```
 
-pub use session::{CreateBuilder, Session};
+#![allow(clippy::needless_doctest_main)]
+
+pub use dynamic::DynSession;
+pub use session::{CreateBuilder, ListIter, Session};
 pub use set::IPSet;
 
 #[allow(non_camel_case_types)]
 #[allow(unused)]
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
+#[allow(clippy::upper_case_acronyms)]
 mod binding;
+mod dynamic;
+mod netlink;
+pub mod savefile;
+#[cfg(feature = "serde")]
+mod serialize;
 mod session;
 mod set;
 pub mod types;
+mod xml;
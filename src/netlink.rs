@@ -0,0 +1,614 @@
+//! Raw netlink requests of the ipset subsystem, for what libipset does not expose, like the
+//! protocol versions of the kernel or several data commands in flight.
+
+use std::ffi::CStr;
+use std::io;
+use std::mem::size_of;
+use std::os::raw::c_int;
+
+use crate::binding;
+use crate::types::Error;
+
+/// The ipset subsystem of nfnetlink, from `linux/netfilter/nfnetlink.h`.
+const NFNL_SUBSYS_IPSET: u16 = 6;
+/// Length of `struct nlmsghdr`.
+const NLMSG_HDRLEN: usize = 16;
+/// Length of `struct nfgenmsg`.
+const NFGENMSG_LEN: usize = 4;
+/// Length of `struct nlattr`.
+const NLA_HDRLEN: usize = 4;
+/// Size of the buffer for the replies, as `MNL_SOCKET_BUFFER_SIZE` of libmnl.
+const RECV_BUFFER_SIZE: usize = 8192;
+/// Seconds to wait for a reply before giving up, the kernel answers all the requests.
+const RECV_TIMEOUT: libc::time_t = 5;
+
+/// Round `len` up to the alignment of the netlink messages and attributes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netlink request of an ipset command, the attributes are appended by `put`.
+pub(crate) struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    /// Start a request of `cmd` with sequence number `seq`, the `protocol` attribute required
+    /// by all the commands is put first.
+    pub fn new(cmd: binding::ipset_cmd, flags: c_int, seq: u32, protocol: u8) -> Request {
+        let mut buf = Vec::with_capacity(64);
+        // the length is set by `finish`.
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&(NFNL_SUBSYS_IPSET << 8 | cmd as u16).to_ne_bytes());
+        buf.extend_from_slice(&((libc::NLM_F_REQUEST | flags) as u16).to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg of family, version and resource id, libipset always sends AF_INET.
+        buf.extend_from_slice(&[libc::AF_INET as u8, 0, 0, 0]);
+        let mut request = Request { buf };
+        request.put(binding::IPSET_ATTR_PROTOCOL, &[protocol]);
+        request
+    }
+
+    /// Append attribute `typ` with `value`.
+    pub fn put(&mut self, typ: u32, value: &[u8]) {
+        put_attribute(&mut self.buf, typ, value);
+    }
+
+    /// Get the message with its length set.
+    pub fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Append attribute `typ` with `value` to `buf`.
+fn put_attribute(buf: &mut Vec<u8>, typ: u32, value: &[u8]) {
+    let len = NLA_HDRLEN + value.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&(typ as u16).to_ne_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// Kind of the value of a data attribute.
+#[derive(Clone, Copy)]
+enum Value {
+    Ip,
+    U8,
+    U16,
+    U32,
+    U64,
+    Ether,
+    Str,
+}
+
+/// The options of the data of an entry and their attributes, as `adt_attrs` of libipset.
+const DATA_ATTRIBUTES: &[(binding::ipset_opt, u32, Value)] = &[
+    (
+        binding::ipset_opt_IPSET_OPT_IP,
+        binding::IPSET_ATTR_IP,
+        Value::Ip,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_IP_TO,
+        binding::IPSET_ATTR_IP_TO,
+        Value::Ip,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_CIDR,
+        binding::IPSET_ATTR_CIDR,
+        Value::U8,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_PORT,
+        binding::IPSET_ATTR_PORT,
+        Value::U16,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_PORT_TO,
+        binding::IPSET_ATTR_PORT_TO,
+        Value::U16,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_PROTO,
+        binding::IPSET_ATTR_PROTO,
+        Value::U8,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_MARK,
+        binding::IPSET_ATTR_MARK,
+        Value::U32,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_ETHER,
+        binding::IPSET_ATTR_ETHER,
+        Value::Ether,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_NAME,
+        binding::IPSET_ATTR_NAME,
+        Value::Str,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_NAMEREF,
+        binding::IPSET_ATTR_NAMEREF,
+        Value::Str,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_IP2,
+        binding::IPSET_ATTR_IP2,
+        Value::Ip,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_CIDR2,
+        binding::IPSET_ATTR_CIDR2,
+        Value::U8,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_IP2_TO,
+        binding::IPSET_ATTR_IP2_TO,
+        Value::Ip,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_IFACE,
+        binding::IPSET_ATTR_IFACE,
+        Value::Str,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_TIMEOUT,
+        binding::IPSET_ATTR_TIMEOUT,
+        Value::U32,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_BYTES,
+        binding::IPSET_ATTR_BYTES,
+        Value::U64,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_PACKETS,
+        binding::IPSET_ATTR_PACKETS,
+        Value::U64,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_ADT_COMMENT,
+        binding::IPSET_ATTR_COMMENT,
+        Value::Str,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_SKBMARK,
+        binding::IPSET_ATTR_SKBMARK,
+        Value::U64,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_SKBPRIO,
+        binding::IPSET_ATTR_SKBPRIO,
+        Value::U32,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_SKBQUEUE,
+        binding::IPSET_ATTR_SKBQUEUE,
+        Value::U16,
+    ),
+];
+
+/// The flag options of the data and their bits in `IPSET_ATTR_CADT_FLAGS`.
+const DATA_FLAGS: &[(binding::ipset_opt, u32)] = &[
+    (
+        binding::ipset_opt_IPSET_OPT_BEFORE,
+        binding::IPSET_FLAG_BEFORE,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_PHYSDEV,
+        binding::IPSET_FLAG_PHYSDEV,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_NOMATCH,
+        binding::IPSET_FLAG_NOMATCH,
+    ),
+    (
+        binding::ipset_opt_IPSET_OPT_IFACE_WILDCARD,
+        binding::IPSET_FLAG_IFACE_WILDCARD,
+    ),
+];
+
+/// Build the request of `cmd` for the entry in `data` of set `name`, as libipset sends a single
+/// entry. The integers are kept in the host order by libipset and sent in the network order.
+/// A port without protocol is sent with tcp, like the ipset command does.
+///
+/// # Safety
+/// `data` must be the data of a session, set by `SetData::set_data`.
+pub(crate) unsafe fn data_request(
+    data: *const binding::ipset_data,
+    cmd: binding::ipset_cmd,
+    name: &CStr,
+    seq: u32,
+    protocol: u8,
+) -> Request {
+    let mut request = Request::new(cmd, libc::NLM_F_ACK, seq, protocol);
+    request.put(binding::IPSET_ATTR_SETNAME, name.to_bytes_with_nul());
+    let net = libc::NLA_F_NET_BYTEORDER as u32;
+    let mut attributes = vec![];
+    for (opt, typ, value) in DATA_ATTRIBUTES {
+        let p = binding::ipset_data_get(data, *opt);
+        if p.is_null() {
+            continue;
+        }
+        match value {
+            Value::Ip => {
+                let (addr, len) = if binding::ipset_data_family(data) == binding::NFPROTO_IPV4 as u8
+                {
+                    (binding::IPSET_ATTR_IPADDR_IPV4, 4)
+                } else {
+                    (binding::IPSET_ATTR_IPADDR_IPV6, 16)
+                };
+                let mut ip = vec![];
+                put_attribute(
+                    &mut ip,
+                    addr | net,
+                    std::slice::from_raw_parts(p as *const u8, len),
+                );
+                put_attribute(&mut attributes, typ | libc::NLA_F_NESTED as u32, &ip);
+            }
+            Value::U8 => put_attribute(&mut attributes, *typ, &[*(p as *const u8)]),
+            Value::U16 => {
+                let value = *(p as *const u16);
+                put_attribute(&mut attributes, typ | net, &value.to_be_bytes());
+            }
+            Value::U32 => {
+                let value = *(p as *const u32);
+                put_attribute(&mut attributes, typ | net, &value.to_be_bytes());
+            }
+            Value::U64 => {
+                let value = *(p as *const u64);
+                put_attribute(&mut attributes, typ | net, &value.to_be_bytes());
+            }
+            Value::Ether => put_attribute(
+                &mut attributes,
+                *typ,
+                std::slice::from_raw_parts(p as *const u8, 6),
+            ),
+            Value::Str => put_attribute(
+                &mut attributes,
+                *typ,
+                CStr::from_ptr(p as _).to_bytes_with_nul(),
+            ),
+        }
+    }
+    if binding::ipset_data_test(data, binding::ipset_opt_IPSET_OPT_PORT)
+        && !binding::ipset_data_test(data, binding::ipset_opt_IPSET_OPT_PROTO)
+    {
+        put_attribute(
+            &mut attributes,
+            binding::IPSET_ATTR_PROTO,
+            &[libc::IPPROTO_TCP as u8],
+        );
+    }
+    let flags = DATA_FLAGS
+        .iter()
+        .filter(|(opt, _)| binding::ipset_data_test(data, *opt))
+        .fold(0u32, |flags, (_, flag)| flags | flag);
+    if flags != 0 {
+        put_attribute(
+            &mut attributes,
+            binding::IPSET_ATTR_CADT_FLAGS | net,
+            &flags.to_be_bytes(),
+        );
+    }
+    request.put(
+        binding::IPSET_ATTR_DATA | libc::NLA_F_NESTED as u32,
+        &attributes,
+    );
+    request
+}
+
+/// A netlink message received from the kernel.
+pub(crate) struct Reply<'a> {
+    pub typ: u16,
+    pub seq: u32,
+    pub payload: &'a [u8],
+}
+
+impl Reply<'_> {
+    /// The errno of an error message, which is 0 for an acknowledgement, or `None` if the
+    /// message is not an error.
+    pub fn errno(&self) -> Option<i32> {
+        if self.typ == libc::NLMSG_ERROR as u16 && self.payload.len() >= 4 {
+            let error = i32::from_ne_bytes(self.payload[..4].try_into().unwrap());
+            Some(-error)
+        } else {
+            None
+        }
+    }
+
+    /// The attributes of an ipset message as `(type, value)`, the flags are cleared from the type.
+    pub fn attributes(&self) -> Vec<(u32, &[u8])> {
+        let mut attributes = vec![];
+        let mut rest = self.payload.get(NFGENMSG_LEN..).unwrap_or_default();
+        while rest.len() >= NLA_HDRLEN {
+            let len = u16::from_ne_bytes([rest[0], rest[1]]) as usize;
+            if len < NLA_HDRLEN || len > rest.len() {
+                break;
+            }
+            let typ = u16::from_ne_bytes([rest[2], rest[3]]) as c_int & libc::NLA_TYPE_MASK;
+            attributes.push((typ as u32, &rest[NLA_HDRLEN..len]));
+            rest = &rest[align(len).min(rest.len())..];
+        }
+        attributes
+    }
+
+    /// Get the value of attribute `typ`.
+    pub fn attribute(&self, typ: u32) -> Option<&[u8]> {
+        self.attributes()
+            .into_iter()
+            .find(|(t, _)| *t == typ)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Split the netlink messages received in `buf`.
+pub(crate) fn parse_replies(buf: &[u8]) -> Result<Vec<Reply<'_>>, Error> {
+    let mut replies = vec![];
+    let mut rest = buf;
+    while rest.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(rest[..4].try_into().unwrap()) as usize;
+        if len < NLMSG_HDRLEN || len > rest.len() {
+            return Err(Error::InvalidOutput(format!(
+                "netlink message of length {}",
+                len
+            )));
+        }
+        replies.push(Reply {
+            typ: u16::from_ne_bytes([rest[4], rest[5]]),
+            seq: u32::from_ne_bytes(rest[8..12].try_into().unwrap()),
+            payload: &rest[NLMSG_HDRLEN..len],
+        });
+        rest = &rest[align(len).min(rest.len())..];
+    }
+    Ok(replies)
+}
+
+/// A netfilter netlink socket, closed on drop.
+pub(crate) struct Socket {
+    fd: c_int,
+}
+
+impl Socket {
+    pub fn open() -> Result<Socket, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // Only the header of a failed request is echoed back in the error, if supported.
+        let on: c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_NETLINK,
+                libc::NETLINK_CAP_ACK,
+                &on as *const _ as _,
+                size_of::<c_int>() as _,
+            );
+        }
+        let socket = Socket { fd };
+        let timeout = libc::timeval {
+            tv_sec: RECV_TIMEOUT,
+            tv_usec: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as _,
+                size_of::<libc::timeval>() as _,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(socket)
+    }
+
+    /// Send `buf` to the kernel, it may hold several requests.
+    pub fn send(&self, buf: &[u8]) -> Result<(), Error> {
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as _;
+        let ret = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as _,
+                buf.len(),
+                0,
+                &addr as *const _ as _,
+                size_of::<libc::sockaddr_nl>() as _,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Receive a datagram into `buf`, it may hold several replies. Fails with `WouldBlock`
+    /// when nothing arrives in `RECV_TIMEOUT` seconds.
+    pub fn recv<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], Error> {
+        loop {
+            let ret = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as _, buf.len(), 0) };
+            if ret >= 0 {
+                return Ok(&buf[..ret as usize]);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Get the protocol versions `(min, max)` of the kernel with `IPSET_CMD_PROTOCOL`.
+pub(crate) fn protocol(socket: &Socket) -> Result<(u8, u8), Error> {
+    let seq = 1;
+    let request = Request::new(
+        binding::ipset_cmd_IPSET_CMD_PROTOCOL,
+        0,
+        seq,
+        binding::IPSET_PROTOCOL as u8,
+    );
+    socket.send(&request.finish())?;
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    loop {
+        let data = socket.recv(&mut buf)?;
+        if let Some(reply) = parse_replies(data)?
+            .iter()
+            .find(|reply| reply.seq == seq && reply.errno() != Some(0))
+        {
+            return parse_protocol(reply);
+        }
+    }
+}
+
+/// Send the `requests` at once and get the errno of their acknowledgements in order, which is
+/// 0 for a success. The sequence numbers of the requests are `count` numbers from `seq`.
+pub(crate) fn acks(
+    socket: &Socket,
+    requests: &[u8],
+    seq: u32,
+    count: usize,
+) -> Result<Vec<i32>, Error> {
+    socket.send(requests)?;
+    let mut errnos = vec![None; count];
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    while errnos.iter().any(Option::is_none) {
+        let data = socket.recv(&mut buf)?;
+        collect_acks(&parse_replies(data)?, seq, &mut errnos);
+    }
+    Ok(errnos.into_iter().map(Option::unwrap_or_default).collect())
+}
+
+/// Keep the errno of the acknowledgements in `replies` into `errnos` by their sequence numbers
+/// from `seq`, the other messages are skipped.
+fn collect_acks(replies: &[Reply], seq: u32, errnos: &mut [Option<i32>]) {
+    for reply in replies {
+        let index = reply.seq.wrapping_sub(seq) as usize;
+        if let (Some(errno), Some(slot)) = (reply.errno(), errnos.get_mut(index)) {
+            slot.get_or_insert(errno);
+        }
+    }
+}
+
+/// Parse the reply of `IPSET_CMD_PROTOCOL`, old kernels do not report the minimal version.
+pub(crate) fn parse_protocol(reply: &Reply) -> Result<(u8, u8), Error> {
+    if let Some(errno) = reply.errno() {
+        return Err(io::Error::from_raw_os_error(errno).into());
+    }
+    let max = reply
+        .attribute(binding::IPSET_ATTR_PROTOCOL)
+        .and_then(|value| value.first().copied())
+        .ok_or_else(|| Error::InvalidOutput("protocol not found in reply".into()))?;
+    let min = reply
+        .attribute(binding::IPSET_ATTR_PROTOCOL_MIN)
+        .and_then(|value| value.first().copied())
+        .unwrap_or(max);
+    Ok((min, max))
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::binding;
+    use crate::netlink::{collect_acks, parse_protocol, parse_replies, put_attribute, Request};
+
+    #[test]
+    fn test_protocol() {
+        let request = Request::new(binding::ipset_cmd_IPSET_CMD_PROTOCOL, 0, 1, 7).finish();
+        assert_eq!(request.len(), 28);
+        assert_eq!(&request[..8], &[28, 0, 0, 0, 1, 6, 1, 0]);
+        assert_eq!(&request[16..], &[2, 0, 0, 0, 5, 0, 1, 0, 7, 0, 0, 0]);
+
+        // A kernel of an older protocol than libipset.
+        let library = (
+            binding::IPSET_PROTOCOL_MIN as u8,
+            binding::IPSET_PROTOCOL as u8,
+        );
+        let kernel = library.1 - 1;
+        let mut reply = Request::new(binding::ipset_cmd_IPSET_CMD_PROTOCOL, 0, 1, kernel);
+        reply.put(binding::IPSET_ATTR_PROTOCOL_MIN, &[kernel]);
+        let reply = reply.finish();
+        let replies = parse_replies(&reply).unwrap();
+        assert_eq!(replies.len(), 1);
+        let version = parse_protocol(&replies[0]).unwrap();
+        assert_eq!(version, (kernel, kernel));
+        assert_ne!(version, library);
+
+        let reply = Request::new(binding::ipset_cmd_IPSET_CMD_PROTOCOL, 0, 1, 6).finish();
+        assert_eq!(
+            parse_protocol(&parse_replies(&reply).unwrap()[0]).unwrap(),
+            (6, 6)
+        );
+
+        // An error of EPERM.
+        let mut error = reply.clone();
+        error[4..6].copy_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+        error[16..20].copy_from_slice(&(-libc::EPERM).to_ne_bytes());
+        let replies = parse_replies(&error).unwrap();
+        assert_eq!(replies[0].errno(), Some(libc::EPERM));
+        assert!(parse_protocol(&replies[0]).is_err());
+        assert!(parse_replies(&reply[..12]).unwrap().is_empty());
+        assert!(parse_replies(&reply[..20]).is_err());
+        assert!(parse_replies(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_acks() {
+        let ack = |seq: u32, errno: i32| {
+            let mut ack = Request::new(binding::ipset_cmd_IPSET_CMD_TEST, 0, seq, 7).finish();
+            ack[4..6].copy_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+            ack[16..20].copy_from_slice(&(-errno).to_ne_bytes());
+            ack
+        };
+        let exist = binding::ipset_errno_IPSET_ERR_EXIST as i32;
+        // The acknowledgements of a datagram, out of order and with a foreign one.
+        let mut data = ack(12, exist);
+        data.extend(ack(10, 0));
+        data.extend(ack(3, libc::EPERM));
+        data.extend(Request::new(binding::ipset_cmd_IPSET_CMD_TEST, 0, 11, 7).finish());
+        let mut errnos = vec![None; 3];
+        collect_acks(&parse_replies(&data).unwrap(), 10, &mut errnos);
+        assert_eq!(errnos, vec![Some(0), None, Some(exist)]);
+        collect_acks(
+            &parse_replies(&ack(11, libc::ENOENT)).unwrap(),
+            10,
+            &mut errnos,
+        );
+        assert_eq!(errnos, vec![Some(0), Some(libc::ENOENT), Some(exist)]);
+    }
+
+    #[test]
+    fn test_put_attribute() {
+        let mut ip = vec![];
+        put_attribute(&mut ip, binding::IPSET_ATTR_IPADDR_IPV4, &[10, 0, 0, 1]);
+        let mut data = vec![];
+        put_attribute(&mut data, binding::IPSET_ATTR_IP, &ip);
+        put_attribute(&mut data, binding::IPSET_ATTR_CIDR, &[24]);
+        assert_eq!(
+            data,
+            vec![12, 0, 1, 0, 8, 0, 1, 0, 10, 0, 0, 1, 5, 0, 3, 0, 24, 0, 0, 0]
+        );
+    }
+}
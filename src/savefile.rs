@@ -0,0 +1,243 @@
+//! Parser and writer for the `ipset save` format, which work without libipset.
+
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::types::{
+    split_fields, AddOption, DynEntry, Error, ListHeader, Parse, SetTypeDescriptor,
+};
+
+/// An entry of a set in the save file.
+pub struct SavedEntry {
+    pub data: DynEntry,
+    pub options: Vec<AddOption>,
+}
+
+/// A set in the save file, with the header of the `create` line and the entries of the `add` lines.
+pub struct SavedSet {
+    pub name: String,
+    pub descriptor: &'static SetTypeDescriptor,
+    pub header: ListHeader,
+    pub entries: Vec<SavedEntry>,
+}
+
+impl SavedSet {
+    /// Parse the `header` of the `create` line of set `name` with type `typename`.
+    fn parse(name: &str, typename: &str, header: &[&str]) -> Result<SavedSet, Error> {
+        let descriptor = SetTypeDescriptor::parse(typename)?;
+        let options = descriptor.create_options();
+        let mut i = 0;
+        while i < header.len() {
+            match header[i] {
+                flag @ ("counters" | "comment" | "forceadd" | "skbinfo")
+                    if options.contains(&flag) =>
+                {
+                    i += 1
+                }
+                key if options.contains(&key) && i + 1 < header.len() => i += 2,
+                key => {
+                    return Err(Error::CAOption(format!(
+                        "{} is not supported by {}",
+                        key, descriptor.typename
+                    )))
+                }
+            }
+        }
+        Ok(SavedSet {
+            name: name.to_string(),
+            descriptor,
+            header: header.join(" ").parse()?,
+            entries: vec![],
+        })
+    }
+
+    /// Parse the `fields` of an `add` line of the set.
+    fn parse_entry(&self, fields: &[&str]) -> Result<SavedEntry, Error> {
+        let (data, options) = fields
+            .split_first()
+            .ok_or_else(|| Error::InvalidOutput(fields.join(" ")))?;
+        let mut entry = DynEntry::template(self.descriptor.typename)?;
+        entry.parse(data)?;
+        let options = AddOption::parse_all(options)?;
+        options.iter().try_for_each(AddOption::validate)?;
+        self.descriptor.check_extensions(&options)?;
+        self.check_enabled(&options)?;
+        Ok(SavedEntry {
+            data: entry,
+            options,
+        })
+    }
+
+    /// Check that the extensions in `options` are enabled by the header of the set, like the
+    /// counters for `packets` and `bytes`.
+    fn check_enabled(&self, options: &[AddOption]) -> Result<(), Error> {
+        let header = &self.header;
+        for option in options {
+            let enabled = match option {
+                AddOption::Timeout(_) => header.timeout.is_some(),
+                AddOption::Bytes(_) | AddOption::Packets(_) => header.counters,
+                AddOption::Comment(_) => header.comment,
+                AddOption::SkbMark(..) | AddOption::SkbPrio(..) | AddOption::SkbQueue(_) => {
+                    header.skbinfo
+                }
+                AddOption::Nomatch | AddOption::Wildcard => true,
+            };
+            if !enabled {
+                return Err(Error::CAOption(format!(
+                    "{} is not enabled by set {}",
+                    option.name(),
+                    self.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for SavedSet {
+    /// Format the set as the `create` line and `add` lines.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let header = self.header.to_string();
+        if header.is_empty() {
+            writeln!(f, "create {} {}", self.name, self.descriptor.typename)?;
+        } else {
+            writeln!(
+                f,
+                "create {} {} {}",
+                self.name, self.descriptor.typename, header
+            )?;
+        }
+        for entry in &self.entries {
+            write!(f, "add {} {}", self.name, entry.data)?;
+            for option in &entry.options {
+                write!(f, " {}", option)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Content of a file in the `ipset save` format, the sets are in the order of the file.
+#[derive(Default)]
+pub struct SaveFile {
+    pub sets: Vec<SavedSet>,
+}
+
+impl Display for SaveFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for set in &self.sets {
+            write!(f, "{}", set)?;
+        }
+        Ok(())
+    }
+}
+
+impl SaveFile {
+    /// Parse the save file from `s`, see `from_reader`.
+    pub fn parse(s: &str) -> Result<SaveFile, Error> {
+        Self::from_reader(s.as_bytes())
+    }
+
+    /// Parse the save file from `reader`, the failed line is reported with `Error::RestoreLine`.
+    /// The sets must be created before the entries are added to them.
+    pub fn from_reader(reader: impl Read) -> Result<SaveFile, Error> {
+        let mut file = SaveFile::default();
+        for (index, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            file.parse_line(&line).map_err(|err| Error::RestoreLine {
+                line: index + 1,
+                text: line.clone(),
+                message: err.to_string(),
+            })?;
+        }
+        Ok(file)
+    }
+
+    /// Parse a `create` or `add` line, the empty and comment lines are ignored.
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let fields = split_fields(line)?;
+        match fields.as_slice() {
+            [] => {}
+            [first, ..] if first.starts_with('#') => {}
+            ["create", name, typename, header @ ..] => {
+                if self.set(name).is_some() {
+                    return Err(Error::SetExists(name.to_string()));
+                }
+                self.sets.push(SavedSet::parse(name, typename, header)?);
+            }
+            ["add", name, entry @ ..] => {
+                let set = self
+                    .sets
+                    .iter_mut()
+                    .find(|set| set.name == *name)
+                    .ok_or_else(|| Error::InvalidOutput(format!("set {} is not created", name)))?;
+                let entry = set.parse_entry(entry)?;
+                set.entries.push(entry);
+            }
+            _ => return Err(Error::InvalidOutput(line.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Get the set `name`.
+    pub fn set(&self, name: &str) -> Option<&SavedSet> {
+        self.sets.iter().find(|set| set.name == name)
+    }
+
+    /// Write the save file to `writer`, which can be restored by `ipset restore`.
+    pub fn write_to(&self, mut writer: impl Write) -> Result<(), Error> {
+        write!(writer, "{}", self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::savefile::SaveFile;
+    use crate::types::{AddOption, DataKind};
+
+    #[test]
+    fn test_save_file() {
+        let content = "create test hash:ip,port family inet hashsize 1024 maxelem 65536 timeout 300 counters comment bucketsize 12 initval 0x1d2c3b4a\n\
+            add test 192.168.3.1,tcp:80 timeout 100 packets 1 bytes 60 comment \"web server\"\n\
+            add test 192.168.3.2,udp:53 timeout 0 packets 0 bytes 0\n\
+            create ports bitmap:port range 1-1024\n\
+            add ports 22\n";
+        let file = SaveFile::parse(content).unwrap();
+        assert_eq!(file.to_string(), content);
+
+        let set = file.set("test").unwrap();
+        assert_eq!(set.descriptor.data_types, &[DataKind::Ip, DataKind::Port]);
+        assert_eq!(set.header.timeout, Some(300));
+        assert_eq!(set.entries.len(), 2);
+        assert!(
+            matches!(set.entries[0].options[3], AddOption::Comment(ref c) if c == "web server")
+        );
+    }
+
+    #[test]
+    fn test_save_file_error() {
+        assert!(SaveFile::parse("add test 192.168.3.1\n").is_err());
+        assert!(SaveFile::parse("create test hash:ip range 1-2\n").is_err());
+        assert!(SaveFile::parse("create test hash:mac\nadd test 192.168.3.1\n").is_err());
+        assert!(SaveFile::parse("create test hash:ip\nadd test 192.168.3.1 nomatch\n").is_err());
+        assert!(SaveFile::parse("create ports bitmap:port range 1-1024 forceadd\n").is_err());
+        assert!(SaveFile::parse("create test list:set forceadd\n").is_err());
+        // the extensions must be enabled by the header.
+        for entry in [
+            "timeout 10",
+            "packets 1 bytes 60",
+            "comment \"a\"",
+            "skbmark 0x10",
+        ] {
+            let content = format!("create test hash:ip\nadd test 192.168.3.1 {}\n", entry);
+            assert!(SaveFile::parse(&content).is_err());
+        }
+        assert!(
+            SaveFile::parse("create test hash:ip comment\nadd test 192.168.3.1 comment \"a\n")
+                .is_err()
+        );
+    }
+}
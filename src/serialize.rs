@@ -0,0 +1,204 @@
+//! `serde` support enabled by the `serde` feature. The data types and entries are serialized as
+//! strings in the ipset notation, like `192.168.3.0/24,tcp:80`, the others are derived.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::types::{
+    DynEntry, IfaceDataType, IpDataType, MacDataType, MarkDataType, NetDataType, Parse,
+    PortDataType, SetDataType,
+};
+
+/// The data in the ipset notation, which is `Display` for a single data, and the items joined
+/// by commas for a tuple, like `192.168.3.1,tcp:80` for `(IpDataType, PortDataType)`.
+pub(crate) trait Notation {
+    fn notation(&self) -> String;
+}
+
+macro_rules! impl_notation {
+    ($($ty:ty),+) => {
+        $(
+            impl Notation for $ty {
+                fn notation(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )+
+    };
+}
+
+impl_notation!(
+    IpDataType,
+    NetDataType,
+    MacDataType,
+    PortDataType,
+    IfaceDataType,
+    MarkDataType,
+    SetDataType,
+    DynEntry
+);
+
+macro_rules! impl_tuple_notation {
+    ($($types:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($types),+> Notation for ($($types),+)
+            where $($types: Notation),+ {
+            fn notation(&self) -> String {
+                let ($($types),+) = self;
+                [$($types.notation(),)+].join(",")
+            }
+        }
+    };
+}
+
+impl_tuple_notation!(A, B);
+impl_tuple_notation!(A, B, C);
+
+/// An entry serialized in the ipset notation, which is required by the tuple entries like
+/// `(IpDataType, PortDataType)`, as the serialization of the tuples is defined by `serde`.
+#[derive(Debug, Clone, Default)]
+pub struct Entry<D>(pub D);
+
+impl<D: Notation> Serialize for Entry<D> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.notation())
+    }
+}
+
+impl<'de, D: Parse + Default> Deserialize<'de> for Entry<D> {
+    fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse(&s).map(Entry).map_err(De::Error::custom)
+    }
+}
+
+/// Parse `s` into a new data.
+fn parse<D: Parse + Default>(s: &str) -> Result<D, crate::types::Error> {
+    let mut data = D::default();
+    data.parse(s)?;
+    Ok(data)
+}
+
+macro_rules! impl_serde {
+    ($($ty:ty),+) => {
+        $(
+            impl Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.serialize_str(&self.to_string())
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    Entry::deserialize(deserializer).map(|entry| entry.0)
+                }
+            }
+        )+
+    };
+}
+
+impl_serde!(
+    IpDataType,
+    NetDataType,
+    MacDataType,
+    PortDataType,
+    IfaceDataType,
+    MarkDataType,
+    SetDataType
+);
+
+/// Serialize the members of `NormalListResult` as pairs of the entry in the ipset notation
+/// and the extensions.
+pub(crate) mod items {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::serialize::{parse, Notation};
+    use crate::types::{EntryExtensions, Parse};
+
+    pub fn serialize<D, S>(
+        items: &Option<Vec<(D, EntryExtensions)>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        D: Notation,
+        S: Serializer,
+    {
+        match items {
+            Some(items) => serializer.collect_seq(
+                items
+                    .iter()
+                    .map(|(data, extensions)| (data.notation(), extensions)),
+            ),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D, De>(
+        deserializer: De,
+    ) -> Result<Option<Vec<(D, EntryExtensions)>>, De::Error>
+    where
+        D: Parse + Default,
+        De: Deserializer<'de>,
+    {
+        let items: Option<Vec<(String, EntryExtensions)>> = Option::deserialize(deserializer)?;
+        items
+            .map(|items| {
+                items
+                    .into_iter()
+                    .map(|(data, extensions)| Ok((parse(&data)?, extensions)))
+                    .collect::<Result<_, crate::types::Error>>()
+                    .map_err(De::Error::custom)
+            })
+            .transpose()
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use crate::serialize::Entry;
+    use crate::types::{
+        AddOption, EntryExtensions, HashIpPort, IpDataType, NetDataType, NormalListResult,
+        PortDataType,
+    };
+
+    #[test]
+    fn test_serde() {
+        let net: NetDataType = serde_json::from_str("\"192.168.3.0/24\"").unwrap();
+        assert_eq!(serde_json::to_string(&net).unwrap(), "\"192.168.3.0/24\"");
+        assert!(serde_json::from_str::<IpDataType>("\"192.168.3\"").is_err());
+
+        let entry: Entry<(IpDataType, PortDataType)> =
+            serde_json::from_str("\"192.168.3.1,tcp:80\"").unwrap();
+        assert_eq!(entry.0 .1.port(), 80);
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            "\"192.168.3.1,tcp:80\""
+        );
+
+        let option = serde_json::to_string(&AddOption::Comment("web".into())).unwrap();
+        assert_eq!(option, "{\"comment\":\"web\"}");
+
+        let result = NormalListResult::<HashIpPort> {
+            name: "test".into(),
+            header: "family inet hashsize 1024 counters".parse().unwrap(),
+            items: Some(vec![(
+                entry.0,
+                EntryExtensions {
+                    timeout: Some(10),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains("[[\"192.168.3.1,tcp:80\",{\"timeout\":10,"));
+        assert!(json.contains("\"family\":\"inet\""));
+        let result: NormalListResult<HashIpPort> = serde_json::from_str(&json).unwrap();
+        assert_eq!(result.header.hash_size, Some(1024));
+        assert!(result.header.counters);
+        let items = result.items.unwrap();
+        assert_eq!(items[0].0 .0.to_string(), "192.168.3.1");
+        assert_eq!(items[0].1.timeout, Some(10));
+    }
+}
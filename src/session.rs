@@ -1,33 +1,25 @@
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::io::Write;
 use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::{self, JoinHandle};
 
 use crate::types::{
-    AddOption, BitmapMethod, EnvOption, Error, HashMethod, IfaceDataType, IpDataType, ListResult,
-    NetDataType, NormalListResult, SetData, SetType, ToCString, TypeName, WithNetmask,
+    AddOption, AddOptions, BitmapMethod, EntryExtensions, EntryOutcome, EnvOption, Error,
+    ErrorKind, HashMethod, IfaceDataType, IpDataType, ListResult, NetDataType, NormalListResult,
+    SetData, SetType, SetTypeDescriptor, ToCString, TypeName, WithHeader, WithNetmask,
 };
-use crate::{binding, IPSet};
-
-/// output function required by libipset to get list output.
-#[no_mangle]
-pub unsafe extern "C" fn ipset_out(
-    p: *mut std::os::raw::c_void,
-    data: *const std::os::raw::c_char,
-    len: i32,
-    cap: i32,
-) {
-    let data = String::from_raw_parts(data as _, len as _, cap as _);
-    let output = (p as *mut Vec<String>).as_mut().unwrap();
-    output.push(data);
-}
+use crate::{binding, netlink, xml, IPSet};
 
 /// This is the main entry for all the operation. I just ignore the ipset struct
-/// because all the operation are performed by session. The `output` field is used
-/// for collecting data for commands like `list`. It is a field for safety.
+/// because all the operation are performed by session. The output of commands like
+/// `list` is collected by `IPSet::capture`.
 pub struct Session<T: SetType> {
     name: CString,
     data: *mut binding::ipset_data,
     set: IPSet,
-    output: Vec<String>,
     _phantom: PhantomData<T>,
     list_name: bool,
 }
@@ -42,7 +34,6 @@ impl<T: SetType> Session<T> {
                 data,
                 set,
                 name: CString::new(name).unwrap(),
-                output: Default::default(),
                 _phantom: Default::default(),
                 list_name: false,
             }
@@ -67,6 +58,12 @@ impl<T: SetType> Session<T> {
         }
     }
 
+    /// Take the warnings reported by libipset on the successful commands since the last call,
+    /// like a downgraded revision of the set type.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        self.set.take_warnings()
+    }
+
     pub(crate) fn set_data(
         &self,
         opt: binding::ipset_opt,
@@ -88,16 +85,12 @@ impl<T: SetType> Session<T> {
         (err, typ == binding::ipset_err_type_IPSET_ERROR)
     }
 
-    fn run_cmd(&mut self, cmd: binding::ipset_cmd) -> Result<(), Error> {
-        unsafe {
-            self.output.clear();
-            if binding::ipset_cmd(self.set.session, cmd, 0) < 0 {
-                let (message, error) = self.error();
-                Err(Error::Cmd(message, error))
-            } else {
-                Ok(())
-            }
-        }
+    fn run_cmd(&self, cmd: binding::ipset_cmd) -> Result<(), Error> {
+        self.run_cmd_at(cmd, 0)
+    }
+
+    fn run_cmd_at(&self, cmd: binding::ipset_cmd, lineno: u32) -> Result<(), Error> {
+        self.set.run_cmd_at(cmd, lineno)
     }
 
     /// Wrapper for ipset_type_get, set OPT_TYPE for the cmd
@@ -105,14 +98,22 @@ impl<T: SetType> Session<T> {
         unsafe {
             let typ = binding::ipset_type_get(self.set.session, cmd);
             if typ.is_null() {
-                let (message, error) = self.error();
+                let (message, error) = self.set.take_error();
                 Err(Error::TypeGet(message, error))
             } else {
+                self.set.keep_warning();
                 Ok(())
             }
         }
     }
 
+    /// Set the name, type and `data` of the command in the session.
+    fn prepare(&self, data: &T::DataType, cmd: binding::ipset_cmd) -> Result<(), Error> {
+        self.set_data(binding::ipset_opt_IPSET_SETNAME, self.name.as_ptr() as _)?;
+        self.get_type(cmd)?;
+        data.set_data(self, None)
+    }
+
     /// Run all the ip related commands, like add/del/test
     fn data_cmd<F>(
         &mut self,
@@ -123,9 +124,7 @@ impl<T: SetType> Session<T> {
     where
         F: FnOnce(&Self) -> Result<(), Error>,
     {
-        self.set_data(binding::ipset_opt_IPSET_SETNAME, self.name.as_ptr() as _)?;
-        self.get_type(cmd)?;
-        data.set_data(self, None)?;
+        self.prepare(&data, cmd)?;
         options(self)?;
         self.run_cmd(cmd)
     }
@@ -134,79 +133,169 @@ impl<T: SetType> Session<T> {
     pub fn test(&mut self, data: impl Into<T::DataType>) -> Result<bool, Error> {
         self.data_cmd(data.into(), binding::ipset_cmd_IPSET_CMD_TEST, |_| Ok(()))
             .map(|_| true)
-            .or_else(|err| {
-                if err.cmd_contains(" is NOT in set ") {
-                    Ok(false)
-                } else {
-                    Err(err)
-                }
+            .or_else(|err| match err {
+                // libipset reports a miss as a warning with the element, the only warning of test.
+                Error::Cmd(_, false) => Ok(false),
+                err if err.kind() == ErrorKind::NotInSet => Ok(false),
+                err => Err(err),
             })
     }
 
-    /// Add `ip` into ipset `name`
+    /// Test if each of `entries` is in ipset `name`, the results are in the order of `entries`.
+    /// libipset sends test commands one by one, so the requests are built from the data of the
+    /// session and sent on a netlink socket `DATA_BATCH` at a time, then mapped back to the
+    /// entries by their acknowledgements. Only a miss is `false`, any other failure is an error.
+    pub fn test_many<I>(&mut self, entries: I) -> Result<Vec<bool>, Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<T::DataType>,
+    {
+        let cmd = binding::ipset_cmd_IPSET_CMD_TEST;
+        let socket = netlink::Socket::open()?;
+        let (_, kernel) = netlink::protocol(&socket)?;
+        let protocol = kernel.min(binding::IPSET_PROTOCOL as u8);
+        let mut entries = entries.into_iter();
+        let mut results = Vec::with_capacity(entries.size_hint().0);
+        // the sequence numbers start after the one of the protocol request.
+        let mut seq = 2;
+        loop {
+            let first = seq;
+            let mut requests = vec![];
+            for entry in entries.by_ref().take(DATA_BATCH) {
+                unsafe {
+                    binding::ipset_data_reset(self.data);
+                }
+                self.prepare(&entry.into(), cmd)?;
+                let request =
+                    unsafe { netlink::data_request(self.data, cmd, &self.name, seq, protocol) };
+                requests.extend(request.finish());
+                seq += 1;
+            }
+            if seq == first {
+                break;
+            }
+            for errno in netlink::acks(&socket, &requests, first, (seq - first) as usize)? {
+                match errno as u32 {
+                    0 => results.push(true),
+                    binding::ipset_errno_IPSET_ERR_EXIST => results.push(false),
+                    _ => {
+                        unsafe {
+                            binding::ipset_data_reset(self.data);
+                        }
+                        return Err(self.set.kernel_error(cmd, errno));
+                    }
+                }
+            }
+        }
+        unsafe {
+            binding::ipset_data_reset(self.data);
+        }
+        Ok(results)
+    }
+
+    /// The set type in the data of the session, known after `prepare`.
+    fn descriptor(&self) -> Option<&'static SetTypeDescriptor> {
+        unsafe {
+            let typename =
+                binding::ipset_data_get(self.data, binding::ipset_opt_IPSET_OPT_TYPENAME);
+            if typename.is_null() {
+                return None;
+            }
+            let typename = CStr::from_ptr(typename as _).to_string_lossy();
+            SetTypeDescriptor::parse(&typename).ok()
+        }
+    }
+
+    /// Check if `option` is supported by the revision of the set, known after `prepare`.
+    fn check_revision(&self, option: &str) -> Result<(), Error> {
+        let revision = unsafe { binding::saved_revision(self.set.session) };
+        if let (Some(descriptor), Ok(revision)) = (self.descriptor(), u8::try_from(revision)) {
+            let required = descriptor.revision(option);
+            if required > revision {
+                return Err(Error::Unsupported {
+                    option: option.into(),
+                    required_revision: required,
+                    kernel_revision: revision,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the add `options` in the session, which must be extensions of the set type.
+    fn set_options(&self, options: &[AddOption]) -> Result<(), Error> {
+        options.iter().try_for_each(AddOption::validate)?;
+        if let Some(descriptor) = self.descriptor() {
+            descriptor.check_extensions(options)?;
+        }
+        for option in options {
+            match option {
+                AddOption::Timeout(timeout) => {
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_TIMEOUT,
+                        timeout as *const _ as _,
+                    )?;
+                }
+                AddOption::Bytes(bytes) => {
+                    self.set_data(binding::ipset_opt_IPSET_OPT_BYTES, bytes as *const _ as _)?;
+                }
+                AddOption::Packets(packets) => {
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_PACKETS,
+                        packets as *const _ as _,
+                    )?;
+                }
+                AddOption::SkbMark(mark, mask) => {
+                    let data = (*mark as u64) << 32 | *mask as u64;
+                    self.set_data(binding::ipset_opt_IPSET_OPT_SKBMARK, &data as *const _ as _)?;
+                }
+                AddOption::SkbPrio(major, minor) => {
+                    let data = (*major as u32) << 16 | *minor as u32;
+                    self.set_data(binding::ipset_opt_IPSET_OPT_SKBPRIO, &data as *const _ as _)?;
+                }
+                AddOption::SkbQueue(queue) => {
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_SKBQUEUE,
+                        queue as *const _ as _,
+                    )?;
+                }
+                AddOption::Comment(comment) => {
+                    let mut comment = comment.clone();
+                    comment.push('\0');
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_ADT_COMMENT,
+                        comment.as_ptr() as _,
+                    )?;
+                }
+                AddOption::Nomatch => {
+                    self.set_data(binding::ipset_opt_IPSET_OPT_NOMATCH, &1 as *const _ as _)?;
+                }
+                AddOption::Wildcard => {
+                    self.check_revision("wildcard")?;
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_IFACE_WILDCARD,
+                        &1 as *const _ as _,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `ip` into ipset `name`, the `options` are a slice of `AddOption` or the
+    /// `EntryExtensions` of a listed entry.
     pub fn add(
         &mut self,
         data: impl Into<T::DataType>,
-        options: &[AddOption],
+        options: &(impl AddOptions + ?Sized),
     ) -> Result<bool, Error> {
+        let options = options.add_options();
         self.data_cmd(data.into(), binding::ipset_cmd_IPSET_CMD_ADD, |session| {
-            for option in options {
-                match option {
-                    AddOption::Timeout(timeout) => {
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_TIMEOUT,
-                            timeout as *const _ as _,
-                        )?;
-                    }
-                    AddOption::Bytes(bytes) => {
-                        session
-                            .set_data(binding::ipset_opt_IPSET_OPT_BYTES, bytes as *const _ as _)?;
-                    }
-                    AddOption::Packets(packets) => {
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_PACKETS,
-                            packets as *const _ as _,
-                        )?;
-                    }
-                    AddOption::SkbMark(mark, mask) => {
-                        let data = (*mark as u64) << 32 | *mask as u64;
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_SKBMARK,
-                            &data as *const _ as _,
-                        )?;
-                    }
-                    AddOption::SkbPrio(major, minor) => {
-                        let data = (*major as u32) << 16 | *minor as u32;
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_SKBPRIO,
-                            &data as *const _ as _,
-                        )?;
-                    }
-                    AddOption::SkbQueue(queue) => {
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_SKBQUEUE,
-                            queue as *const _ as _,
-                        )?;
-                    }
-                    AddOption::Comment(comment) => {
-                        let mut comment = comment.clone();
-                        comment.push('\0');
-                        session.set_data(
-                            binding::ipset_opt_IPSET_OPT_ADT_COMMENT,
-                            comment.as_ptr() as _,
-                        )?;
-                    }
-                    AddOption::Nomatch => {
-                        session
-                            .set_data(binding::ipset_opt_IPSET_OPT_NOMATCH, &1 as *const _ as _)?;
-                    }
-                }
-            }
-            Ok(())
+            session.set_options(&options)
         })
         .map(|_| true)
         .or_else(|err| {
-            if err.cmd_contains("Element cannot be added to the set: it's already added") {
+            if err.kind() == ErrorKind::AlreadyAdded {
                 Ok(false)
             } else {
                 Err(err)
@@ -219,7 +308,7 @@ impl<T: SetType> Session<T> {
         self.data_cmd(ip.into(), binding::ipset_cmd_IPSET_CMD_DEL, |_| Ok(()))
             .map(|_| true)
             .or_else(|err| {
-                if err.cmd_contains("Element cannot be deleted from the set: it's not added") {
+                if err.kind() == ErrorKind::NotAdded {
                     Ok(false)
                 } else {
                     Err(err)
@@ -227,12 +316,116 @@ impl<T: SetType> Session<T> {
             })
     }
 
+    /// Add `entries` into ipset `name` with their own options. The entries are sent on a netlink
+    /// socket `DATA_BATCH` at a time like `test_many`, and an outcome is reported for every entry
+    /// in order, so the failure of a single entry does not stop the others. A failure of the set
+    /// itself, like a missing set, is returned as an error instead.
+    pub fn add_many<I, D>(&mut self, entries: I) -> Result<Vec<EntryOutcome>, Error>
+    where
+        I: IntoIterator<Item = (D, Vec<AddOption>)>,
+        D: Into<T::DataType>,
+    {
+        let entries = entries
+            .into_iter()
+            .map(|(data, options)| (data.into(), options));
+        self.batch_cmd(binding::ipset_cmd_IPSET_CMD_ADD, entries)
+    }
+
+    /// Delete `entries` from ipset `name` in batches, see `add_many`.
+    pub fn del_many<I, D>(&mut self, entries: I) -> Result<Vec<EntryOutcome>, Error>
+    where
+        I: IntoIterator<Item = D>,
+        D: Into<T::DataType>,
+    {
+        let entries = entries.into_iter().map(|data| (data.into(), Vec::new()));
+        self.batch_cmd(binding::ipset_cmd_IPSET_CMD_DEL, entries)
+    }
+
+    /// Run `cmd` for all the `entries` in batches. An entry is `EntryOutcome::Unchanged` if the
+    /// kernel reports `IPSET_ERR_EXIST`, which is already added for add and not added for del.
+    fn batch_cmd(
+        &mut self,
+        cmd: binding::ipset_cmd,
+        entries: impl Iterator<Item = (T::DataType, Vec<AddOption>)>,
+    ) -> Result<Vec<EntryOutcome>, Error> {
+        let result = self.send_batches(cmd, entries);
+        unsafe {
+            binding::ipset_data_reset(self.data);
+        }
+        result
+    }
+
+    /// Send the requests of `entries` in batches, the data of the session is left set.
+    fn send_batches(
+        &self,
+        cmd: binding::ipset_cmd,
+        mut entries: impl Iterator<Item = (T::DataType, Vec<AddOption>)>,
+    ) -> Result<Vec<EntryOutcome>, Error> {
+        let socket = netlink::Socket::open()?;
+        let (_, kernel) = netlink::protocol(&socket)?;
+        let protocol = kernel.min(binding::IPSET_PROTOCOL as u8);
+        let mut outcomes = Vec::with_capacity(entries.size_hint().0);
+        // the sequence numbers start after the one of the protocol request.
+        let mut seq = 2;
+        loop {
+            let first = seq;
+            let count = outcomes.len();
+            let mut requests = vec![];
+            // the index in `outcomes` of the entry of each request.
+            let mut sent = vec![];
+            for (data, options) in entries.by_ref().take(DATA_BATCH) {
+                unsafe {
+                    binding::ipset_data_reset(self.data);
+                }
+                match self
+                    .prepare(&data, cmd)
+                    .and_then(|_| self.set_options(&options))
+                {
+                    Ok(_) => {
+                        let request = unsafe {
+                            netlink::data_request(self.data, cmd, &self.name, seq, protocol)
+                        };
+                        requests.extend(request.finish());
+                        sent.push(outcomes.len());
+                        outcomes.push(EntryOutcome::Applied);
+                        seq += 1;
+                    }
+                    Err(err) if is_set_error(&err) => return Err(err),
+                    Err(err) => outcomes.push(EntryOutcome::Failed(err)),
+                }
+            }
+            if seq == first {
+                if outcomes.len() == count {
+                    break;
+                }
+                continue;
+            }
+            let errnos = netlink::acks(&socket, &requests, first, sent.len())?;
+            for (index, errno) in sent.into_iter().zip(errnos) {
+                if errno == 0 {
+                    continue;
+                }
+                if errno as u32 == binding::ipset_errno_IPSET_ERR_EXIST {
+                    outcomes[index] = EntryOutcome::Unchanged;
+                    continue;
+                }
+                let err = self.set.kernel_error(cmd, errno);
+                if is_set_error(&err) {
+                    return Err(err);
+                }
+                outcomes[index] = EntryOutcome::Failed(err);
+            }
+        }
+        Ok(outcomes)
+    }
+
     /// Run all the name only related command like flush/list/destroy
-    fn name_cmd(&mut self, cmd: binding::ipset_cmd) -> Result<bool, Error> {
+    fn name_cmd(&self, cmd: binding::ipset_cmd) -> Result<bool, Error> {
         self.set_data(binding::ipset_opt_IPSET_SETNAME, self.name.as_ptr() as _)?;
 
         self.run_cmd(cmd).map(|_| true).or_else(|err| {
-            if let Error::Cmd(_, false) = err {
+            if let Error::Cmd(message, false) = err {
+                self.set.warn(&message);
                 Ok(false)
             } else {
                 Err(err)
@@ -240,66 +433,213 @@ impl<T: SetType> Session<T> {
         })
     }
 
-    /// Test if the set already exists.
+    /// Test if the set already exists, only ipset `name` is queried from the kernel.
     pub fn exists(&mut self) -> Result<bool, Error> {
-        let mut unset = false;
-        if !self.list_name {
-            self.set_option(EnvOption::ListSetName);
-            unset = true;
-        }
-        let ret = self.list();
-        if unset {
-            self.unset_option(EnvOption::ListSetName);
-        }
-        match ret? {
-            ListResult::Normal(_) => {
-                unreachable!("normal should not return")
-            }
-            ListResult::Terse(names) => {
-                let name = self.name.to_string_lossy().to_string();
-                Ok(names.contains(&name))
-            }
+        match self.typename() {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::SetNotFound => Ok(false),
+            Err(err) => Err(err),
         }
     }
 
+    /// Run a name only command with output captured in `mode`, return the printed lines.
+    fn output_cmd(
+        &self,
+        cmd: binding::ipset_cmd,
+        mode: binding::ipset_output_mode,
+    ) -> Result<Vec<String>, Error> {
+        self.set.capture(mode, || self.name_cmd(cmd).map(|_| ()))
+    }
+
     /// List all the ips in ipset `name`
     pub fn list(&mut self) -> Result<ListResult<T>, Error> {
-        unsafe {
-            binding::ipset_custom_printf(
-                self.set.set,
-                None,
-                None,
-                Some(binding::print_out),
-                &mut self.output as *mut _ as _,
-            );
-        }
-        self.name_cmd(binding::ipset_cmd_IPSET_CMD_LIST)?;
-        let ret = if self.list_name {
-            let mut names = vec![];
-            for line in &self.output {
-                line.split("\n").for_each(|s| {
-                    if !s.is_empty() {
-                        names.push(s.to_string())
-                    }
-                })
-            }
-            ListResult::Terse(names)
+        self.list_with(T::DataType::default)
+    }
+
+    /// Same as `list`, but the members are parsed into the data created by `template`.
+    pub(crate) fn list_with<F>(&mut self, template: F) -> Result<ListResult<T>, Error>
+    where
+        F: Fn() -> T::DataType,
+    {
+        let lines = self.output_cmd(
+            binding::ipset_cmd_IPSET_CMD_LIST,
+            binding::ipset_output_mode_IPSET_LIST_PLAIN,
+        )?;
+        if self.list_name {
+            Ok(ListResult::Terse(lines))
         } else {
             let mut result = NormalListResult::default();
-            for line in &self.output {
-                for s in line.split("\n") {
-                    if !s.is_empty() {
-                        result.update_from_str(s)?;
-                    }
+            for line in &lines {
+                result.update_with(line, &template)?;
+            }
+            Ok(ListResult::Normal(result))
+        }
+    }
+
+    /// List the members of ipset `name` one by one as the kernel dumps them, instead of
+    /// collecting the whole output like `list`. The list runs in a new thread with its own
+    /// libipset session, which is blocked once `LIST_ITER_LINES` lines are not yet taken,
+    /// so the memory is bounded for sets of any size. `EnvOption::ListSetName` is ignored.
+    pub fn list_iter(&self) -> ListIter<T> {
+        self.list_iter_with(T::DataType::default)
+    }
+
+    /// Same as `list_iter`, but the members are parsed into the data created by `template`.
+    pub(crate) fn list_iter_with<F>(&self, template: F) -> ListIter<T, F>
+    where
+        F: Fn() -> T::DataType,
+    {
+        let options: Vec<_> = [EnvOption::Sorted, EnvOption::Resolve]
+            .into_iter()
+            .filter(|option| unsafe {
+                binding::ipset_envopt_test(self.set.session, option.to_option())
+            })
+            .collect();
+        let name = self.name.clone();
+        let (sender, receiver) = sync_channel(LIST_ITER_LINES);
+        let handle = thread::spawn(move || {
+            let set = IPSet::new();
+            for option in options {
+                unsafe {
+                    binding::ipset_envopt_set(set.session, option.to_option());
                 }
             }
-            ListResult::Normal(result)
-        };
+            if let Err(err) = set.stream_list(&name, sender.clone()) {
+                let _ = sender.send(Err(err));
+            }
+        });
+        ListIter::new(receiver, handle, template)
+    }
+
+    /// Same as `list`, but libipset prints the list in xml, so the header fields and the members
+    /// are decoded without the ambiguity of the plain text, like a comment with spaces.
+    pub fn list_xml(&mut self) -> Result<ListResult<T>, Error> {
+        self.list_xml_with(T::DataType::default)
+    }
+
+    /// Same as `list_xml`, but the members are parsed into the data created by `template`.
+    pub(crate) fn list_xml_with<F>(&mut self, template: F) -> Result<ListResult<T>, Error>
+    where
+        F: Fn() -> T::DataType,
+    {
+        let lines = self.output_cmd(
+            binding::ipset_cmd_IPSET_CMD_LIST,
+            binding::ipset_output_mode_IPSET_LIST_XML,
+        )?;
+        xml::parse_list(&lines.join("\n"), template)
+    }
+
+    /// Get the typename of ipset `name` from the kernel with `IPSET_CMD_HEADER`.
+    pub(crate) fn typename(&self) -> Result<String, Error> {
+        self.set_data(binding::ipset_opt_IPSET_SETNAME, self.name.as_ptr() as _)?;
+        let ret = self
+            .run_cmd(binding::ipset_cmd_IPSET_CMD_HEADER)
+            .and_then(|_| unsafe {
+                let typename =
+                    binding::ipset_data_get(self.data, binding::ipset_opt_IPSET_OPT_TYPENAME);
+                if typename.is_null() {
+                    Err(Error::InvalidOutput("typename not found in header".into()))
+                } else {
+                    Ok(CStr::from_ptr(typename as _).to_string_lossy().to_string())
+                }
+            });
         unsafe {
-            binding::ipset_custom_printf(self.set.set, None, None, None, std::ptr::null_mut());
-            self.output.clear();
+            binding::ipset_data_reset(self.data);
         }
-        Ok(ret)
+        ret
+    }
+
+    /// List ipset `name` without members, regardless of the list options of the session.
+    fn list_header(&mut self) -> Result<NormalListResult<T>, Error> {
+        let list_name = self.list_name;
+        let list_header = unsafe {
+            binding::ipset_envopt_test(self.set.session, EnvOption::ListHeader.to_option())
+        };
+        if list_name {
+            self.unset_option(EnvOption::ListSetName);
+        }
+        self.set_option(EnvOption::ListHeader);
+        let ret = self.list();
+        if !list_header {
+            self.unset_option(EnvOption::ListHeader);
+        }
+        if list_name {
+            self.set_option(EnvOption::ListSetName);
+        }
+        match ret? {
+            ListResult::Normal(result) => Ok(result),
+            ListResult::Terse(_) => unreachable!("terse should not return"),
+        }
+    }
+
+    /// Get the header of ipset `name` without listing the members.
+    /// The type of the set is checked against `T` first, `Error::TypeMismatch` is returned if differs.
+    pub fn header(&mut self) -> Result<<T::Method as WithHeader>::Header, Error>
+    where
+        T::Method: WithHeader + TypeName,
+        T::DataType: TypeName,
+    {
+        let typename = self.typename()?;
+        if typename.as_bytes() != T::to_cstring().as_bytes() {
+            return Err(Error::TypeMismatch(typename));
+        }
+        T::Method::header(self.list_header()?.header)
+    }
+
+    /// Get the `create` line of ipset `name` in the `ipset save` format.
+    fn create_line(&mut self) -> Result<String, Error> {
+        self.set_option(EnvOption::ListHeader);
+        let ret = self.output_cmd(
+            binding::ipset_cmd_IPSET_CMD_LIST,
+            binding::ipset_output_mode_IPSET_LIST_SAVE,
+        );
+        self.unset_option(EnvOption::ListHeader);
+        let lines = ret?;
+        lines
+            .iter()
+            .find(|line| line.starts_with("create "))
+            .cloned()
+            .ok_or_else(|| Error::InvalidOutput(lines.join("\n")))
+    }
+
+    /// Replace all the entries in ipset `name` atomically. A shadow set with the same header is
+    /// created and filled with `entries` by `add_many`, then swapped with ipset `name`, and the
+    /// shadow set, which holds the old entries now, is destroyed. The shadow set is destroyed on
+    /// failure too. The entries are replaced once swapped, so a failure to destroy the shadow set
+    /// is kept in the warnings, see `take_warnings`.
+    pub fn replace_all<I>(&mut self, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<T::DataType>,
+    {
+        let name = self.name.to_string_lossy().to_string();
+        let line = Session::<T>::new(name.clone()).create_line()?;
+        let header = line
+            .strip_prefix(&format!("create {} ", name))
+            .ok_or_else(|| Error::InvalidOutput(line.clone()))?;
+        let mut shadow = Session::<T>::new(shadow_name(&name));
+        shadow.set.parse_line(&format!(
+            "create {} {}",
+            shadow.name.to_string_lossy(),
+            header
+        ))?;
+        let ret = shadow
+            .add_many(entries.into_iter().map(|entry| (entry, Vec::new())))
+            .and_then(|outcomes| {
+                outcomes.into_iter().try_for_each(|outcome| match outcome {
+                    EntryOutcome::Failed(err) => Err(err),
+                    _ => Ok(()),
+                })
+            })
+            .and_then(|_| self.swap(&shadow));
+        if let Err(err) = shadow.destroy() {
+            self.set.warn(&format!(
+                "failed to destroy the shadow set {}: {}",
+                shadow.name.to_string_lossy(),
+                err
+            ));
+        }
+        ret.map(|_| ())
     }
 
     /// Clear all the content in ipset `name`
@@ -312,6 +652,46 @@ impl<T: SetType> Session<T> {
         self.name_cmd(binding::ipset_cmd_IPSET_CMD_DESTROY)
     }
 
+    /// Rename the ipset `name` to the given name, the session follows the set on success.
+    pub fn rename(&mut self, name: String) -> Result<bool, Error> {
+        let name = CString::new(name)?;
+        self.set_data(binding::ipset_opt_IPSET_OPT_SETNAME2, name.as_ptr() as _)?;
+        let ret = self
+            .name_cmd(binding::ipset_cmd_IPSET_CMD_RENAME)
+            .map_err(|err| match (err.kind(), err) {
+                (ErrorKind::SetExists, Error::Cmd(message, ..)) => Error::SetExists(message),
+                (ErrorKind::SetInUse, Error::Cmd(message, ..)) => Error::SetInUse(message),
+                (_, err) => err,
+            })?;
+        if ret {
+            self.name = name;
+        }
+        Ok(ret)
+    }
+
+    /// Swap the content of ipset `name` with the set of `other`, both sessions keep their names.
+    /// The sets must be of the same type, which is enforced by `T`, the kernel check is reported
+    /// as `Error::TypeMismatch` for sets created outside of this crate.
+    pub fn swap(&mut self, other: &Session<T>) -> Result<bool, Error> {
+        self.set_data(
+            binding::ipset_opt_IPSET_OPT_SETNAME2,
+            other.name.as_ptr() as _,
+        )?;
+        self.name_cmd(binding::ipset_cmd_IPSET_CMD_SWAP)
+            .map_err(|err| match (err.kind(), err) {
+                (ErrorKind::TypeMismatch, Error::Cmd(message, ..)) => Error::TypeMismatch(message),
+                (_, err) => err,
+            })
+    }
+
+    /// Save the ipset `name` to `writer` in the `ipset save` format.
+    pub fn save_to_writer(&mut self, writer: impl Write) -> Result<(), Error> {
+        self.set
+            .write_output(binding::ipset_output_mode_IPSET_LIST_SAVE, writer, || {
+                self.name_cmd(binding::ipset_cmd_IPSET_CMD_SAVE).map(|_| ())
+            })
+    }
+
     /// Save the ipset `name` to filename
     pub fn save(&mut self, filename: String) -> Result<bool, Error> {
         unsafe {
@@ -350,27 +730,177 @@ impl<T: SetType> Session<T> {
         T::Method: TypeName,
         T::DataType: TypeName,
     {
+        let typename = T::to_cstring();
+        let descriptor = SetTypeDescriptor::of::<T>()?;
+        let revision = self.set.type_revisions(descriptor.typename)?.max;
         unsafe {
             binding::ipset_data_reset(self.data);
-            let typename = T::to_cstring();
             self.set_data(
                 binding::ipset_opt_IPSET_OPT_TYPENAME,
                 typename.as_ptr() as _,
             )?;
             self.get_type(binding::ipset_cmd_IPSET_CMD_CREATE)?;
         }
-        let builder = CreateBuilder { session: self };
+        let builder = CreateBuilder {
+            session: self,
+            descriptor,
+            revision,
+        };
         f(builder)?;
         self.name_cmd(binding::ipset_cmd_IPSET_CMD_CREATE)
     }
 }
 
-/// Helper for creating a ipset
+/// Whether `err` is a failure of the set rather than of an entry, so the other entries would
+/// fail the same.
+fn is_set_error(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::SetNotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::Protocol
+            | ErrorKind::TypeMismatch
+    )
+}
+
+/// Generate a set name from `name` which is unique in this host and fits in `IPSET_MAXNAMELEN`.
+fn shadow_name(name: &str) -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let suffix = format!(
+        "-{:x}-{:x}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut len = (binding::IPSET_MAXNAMELEN as usize - 1)
+        .saturating_sub(suffix.len())
+        .min(name.len());
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    format!("{}{}", &name[..len], suffix)
+}
+
+/// Requests sent by `Session::test_many` and `Session::add_many` at once, in a single datagram.
+const DATA_BATCH: usize = 64;
+
+/// Lines of the list output buffered by `Session::list_iter` at most.
+const LIST_ITER_LINES: usize = 1024;
+
+/// Iterator over the members of a set returned by `Session::list_iter`. The header lines are
+/// parsed before the first member, and are available by `header` afterwards. The iterator stops
+/// after an error of the list command, while a malformed member is reported and skipped.
+/// If the iterator is dropped early, the list is aborted at the next line printed by libipset
+/// and the listing thread is joined.
+pub struct ListIter<T: SetType, F = fn() -> <T as SetType>::DataType> {
+    receiver: Option<Receiver<Result<String, Error>>>,
+    handle: Option<JoinHandle<()>>,
+    template: F,
+    result: NormalListResult<T>,
+    typename: Option<&'static str>,
+    done: bool,
+}
+
+impl<T: SetType, F> ListIter<T, F>
+where
+    F: Fn() -> T::DataType,
+{
+    fn new(receiver: Receiver<Result<String, Error>>, handle: JoinHandle<()>, template: F) -> Self {
+        Self {
+            receiver: Some(receiver),
+            handle: Some(handle),
+            template,
+            result: NormalListResult::default(),
+            typename: None,
+            done: false,
+        }
+    }
+
+    /// The name, type and header of the set, without the members.
+    pub fn header(&self) -> &NormalListResult<T> {
+        &self.result
+    }
+
+    /// Check the type of the listed set against `typename`, `Error::TypeMismatch` is returned
+    /// instead of the members if differs.
+    pub(crate) fn with_typename(mut self, typename: &'static str) -> Self {
+        self.typename = Some(typename);
+        self
+    }
+
+    /// Parse a header line, the type is checked once the members begin.
+    fn update_header(&mut self, line: &str) -> Result<(), Error> {
+        self.result.update_with(line, &self.template)?;
+        match self.typename {
+            Some(typename) if self.result.items.is_some() && self.result.typ != typename => {
+                Err(Error::TypeMismatch(self.result.typ.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T: SetType, F> Iterator for ListIter<T, F>
+where
+    F: Fn() -> T::DataType,
+{
+    type Item = Result<(T::DataType, EntryExtensions), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let line = match self.receiver.as_ref()?.recv() {
+                Ok(Ok(line)) => line,
+                Ok(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                Err(_) => break,
+            };
+            if self.result.items.is_some() {
+                return Some(self.result.parse_member(&line, &self.template));
+            }
+            if let Err(err) = self.update_header(&line) {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        None
+    }
+}
+
+impl<T: SetType, F> Drop for ListIter<T, F> {
+    /// Close the channel first, so the listing thread blocked on a full channel fails to send
+    /// and aborts the list.
+    fn drop(&mut self) {
+        drop(self.receiver.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Helper for creating a ipset, the options are checked against the revision supported by the
+/// kernel for the set type, `Error::Unsupported` is returned if the kernel is too old.
 pub struct CreateBuilder<'a, T: SetType> {
     session: &'a Session<T>,
+    descriptor: &'static SetTypeDescriptor,
+    revision: u8,
 }
 
 impl<'a, T: SetType> CreateBuilder<'a, T> {
+    /// Check if `option` is supported by the kernel revision of the set type.
+    fn check(&self, option: &str) -> Result<(), Error> {
+        let required = self.descriptor.revision(option);
+        if required > self.revision {
+            Err(Error::Unsupported {
+                option: option.into(),
+                required_revision: required,
+                kernel_revision: self.revision,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
     /// All set types supports the optional timeout parameter when creating a set and adding entries.
     /// The value of the timeout parameter for the create command means the default timeout value (in seconds) for new entries.
     /// If a set is created with timeout support, then the same timeout option can  be  used  to  specify  non-default
@@ -388,6 +918,7 @@ impl<'a, T: SetType> CreateBuilder<'a, T> {
     /// The packet and byte counters are initialized to zero when the elements are (re-)added to the set,
     /// unless the packet and byte counter values are  explicitly specified by the packets and bytes options.
     pub fn with_counters(self) -> Result<Self, Error> {
+        self.check("counters")?;
         self.session
             .set_data(binding::ipset_opt_IPSET_OPT_COUNTERS, &1 as *const _ as _)?;
         Ok(self)
@@ -397,12 +928,15 @@ impl<'a, T: SetType> CreateBuilder<'a, T> {
     /// the metainfo (firewall mark, tc class and hardware queue) with every entry and map it to
     /// packets by usage of SET netfilter target with --map-set option.
     pub fn with_skbinfo(self) -> Result<Self, Error> {
+        self.check("skbinfo")?;
         self.session
             .set_data(binding::ipset_opt_IPSET_OPT_SKBINFO, &1 as *const _ as _)?;
         Ok(self)
     }
 
+    /// All set types support the optional comment extension when creating a set.
     pub fn with_comment(self) -> Result<Self, Error> {
+        self.check("comment")?;
         self.session.set_data(
             binding::ipset_opt_IPSET_OPT_CREATE_COMMENT,
             &1 as *const _ as _,
@@ -467,6 +1001,7 @@ where
     /// which makes possible to build up sets with exceptions.
     pub fn with_nomatch(self) -> Result<Self, Error> {
         if T::DataType::name().contains("net") {
+            self.check("nomatch")?;
             self.session
                 .set_data(binding::ipset_opt_IPSET_OPT_NOMATCH, &1 as *const _ as _)?;
             Ok(self)
@@ -477,21 +1012,69 @@ where
         }
     }
 
+    /// This parameter is valid for the create command of all hash type sets.
+    /// It specifies the maximal number of elements which can be stored in a hash bucket, default 12.
+    pub fn with_bucket_size(self, size: u8) -> Result<Self, Error> {
+        self.check("bucketsize")?;
+        self.session.set_data(
+            binding::ipset_opt_IPSET_OPT_BUCKETSIZE,
+            &size as *const _ as _,
+        )?;
+        Ok(self)
+    }
+
+    /// This parameter is valid for the create command of all hash type sets.
+    /// It specifies the initial value of the hash function, which is random by default.
+    pub fn with_initval(self, initval: u32) -> Result<Self, Error> {
+        self.check("initval")?;
+        self.session.set_data(
+            binding::ipset_opt_IPSET_OPT_INITVAL,
+            &initval as *const _ as _,
+        )?;
+        Ok(self)
+    }
+
     /// All  hash  set types support the optional forceadd parameter when creating a set.  
     /// When sets created with this option become full the next addition to the set may
     /// succeed and evict a random entry from the set.
     pub fn with_forceadd(self) -> Result<Self, Error> {
+        self.check("forceadd")?;
         self.session
             .set_data(binding::ipset_opt_IPSET_OPT_FORCEADD, &1 as *const _ as _)?;
         Ok(self)
     }
+
+    /// The hash:ip, hash:ip,port and hash:net,net set types support the optional bitmask
+    /// parameter when creating a set. The addresses are masked with the bitmask, like
+    /// 255.255.0.255, before they are stored in or matched with the set. The family of the set is
+    /// the one of the bitmask, which can not be used together with netmask.
+    pub fn with_bitmask(self, mask: IpAddr) -> Result<Self, Error> {
+        if !self.descriptor.create_options().contains(&"bitmask") {
+            return Err(Error::CAOption(format!(
+                "bitmask is not supported by {}",
+                self.descriptor.typename
+            )));
+        }
+        self.check("bitmask")?;
+        let mask = IpDataType::from(mask);
+        let (ip, family) = match &mask {
+            IpDataType::IPv4(ip) => (ip as *const _ as _, &binding::NFPROTO_IPV4 as *const _ as _),
+            IpDataType::IPv6(ip) => (ip as *const _ as _, &binding::NFPROTO_IPV6 as *const _ as _),
+        };
+        self.session
+            .set_data(binding::ipset_opt_IPSET_OPT_FAMILY, family)?;
+        self.session
+            .set_data(binding::ipset_opt_IPSET_OPT_BITMASK, ip)?;
+        Ok(self)
+    }
 }
 
 impl<'a, T: SetType<Method = HashMethod, DataType = (NetDataType, IfaceDataType)>>
     CreateBuilder<'a, T>
 {
     /// This flag is valid when adding elements to a hash:net,iface set. If the flag is set,
-    /// then prefix matching is used when comparing with this element.
+    /// then prefix matching is used when comparing with this element. The flag is checked against
+    /// the revision of the set when adding with `AddOption::Wildcard`.
     pub fn with_wildcard(self) -> Result<Self, Error> {
         self.session.set_data(
             binding::ipset_opt_IPSET_OPT_IFACE_WILDCARD,
@@ -519,9 +1102,9 @@ where
     /// instead of IP host addresses. The cidr prefix value must be  between  1-32.  
     /// An IP address will be in the set if the network address, which is resulted by masking the
     /// address with the specified netmask, can be found in the set.
-
     pub fn with_netmask(self, cidr: u8) -> Result<Self, Error> {
-        if cidr >= 1 && cidr <= 32 {
+        self.check("netmask")?;
+        if (1..=32).contains(&cidr) {
             self.session
                 .set_data(binding::ipset_opt_IPSET_OPT_NETMASK, &cidr as *const _ as _)?;
             Ok(self)
@@ -532,3 +1115,32 @@ where
         }
     }
 }
+
+#[allow(unused_imports)]
+mod tests {
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    use crate::session::ListIter;
+    use crate::types::{Error, HashIp, IpDataType};
+
+    #[test]
+    fn test_list_iter_drop() {
+        let (sender, receiver) = sync_channel(1);
+        // like the listing thread, which keeps printing until the receiver is dropped.
+        let handle = thread::spawn(move || {
+            let lines = ["Name: test", "Type: hash:ip", "Members:"];
+            for line in lines.iter().chain(std::iter::repeat(&"10.0.0.1")) {
+                if sender.send(Ok(line.to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut iter: ListIter<HashIp> = ListIter::new(receiver, handle, IpDataType::default);
+        let (data, _) = iter.next().unwrap().unwrap();
+        assert_eq!(data.to_string(), "10.0.0.1");
+        assert_eq!(iter.header().name, "test");
+        // the thread would never end if not aborted by the drop.
+        drop(iter);
+    }
+}
@@ -1,12 +1,160 @@
-use std::ffi::CStr;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
 
-use crate::binding;
-use crate::types::Error;
+use crate::types::{
+    split_line_error, EnvOption, Error, ErrorCode, RestoreFailure, RestoreReport, SetInfo,
+    SetTypeDescriptor, TypeRevision, Version,
+};
+use crate::{binding, netlink};
 
-/// Wrapper for ipset instance in c.
+/// The codes of the messages rendered by `IPSet::learn_error_codes`.
+#[derive(Default)]
+struct ErrorCodes {
+    /// The set types rendered for, `None` for a session without a set type.
+    typenames: HashSet<Option<String>>,
+    /// The messages without the line number, the first code rendered is kept.
+    messages: HashMap<String, ErrorCode>,
+}
+
+impl ErrorCodes {
+    fn insert(&mut self, message: &str, code: ErrorCode) {
+        let message = split_line_error(message).map_or(message, |(_, message)| message);
+        self.messages.entry(message.to_string()).or_insert(code);
+    }
+
+    /// Get the code of `message`, which may have the line number of a restore.
+    fn get(&self, message: &str) -> Option<ErrorCode> {
+        let message = split_line_error(message).map_or(message, |(_, message)| message);
+        self.messages.get(message).copied()
+    }
+}
+
+/// The codes learned by all the sessions, as the messages do not depend on the session.
+fn error_codes() -> MutexGuard<'static, ErrorCodes> {
+    static CODES: OnceLock<Mutex<ErrorCodes>> = OnceLock::new();
+    CODES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Get the code of the error `message` of libipset.
+pub(crate) fn error_code(message: &str) -> Option<ErrorCode> {
+    error_codes().get(message)
+}
+
+/// output function required by libipset to get list output, `data` is copied into the
+/// `OutputBuffer` pointed by `p`. -1 is returned once the output is closed, so libipset aborts
+/// the command.
+#[no_mangle]
+pub unsafe extern "C" fn ipset_out(
+    p: *mut std::os::raw::c_void,
+    data: *const std::os::raw::c_char,
+    len: u32,
+) -> std::os::raw::c_int {
+    if let Some(output) = (p as *mut OutputBuffer).as_mut() {
+        if !data.is_null() {
+            output.push(std::slice::from_raw_parts(data as *const u8, len as usize));
+        }
+        if output.closed {
+            return -1;
+        }
+    }
+    0
+}
+
+/// Output of libipset collected by `print_out`. libipset prints a line in several calls,
+/// so the text is kept until the end of the line.
+#[derive(Default)]
+pub(crate) struct OutputBuffer<'a> {
+    pending: Vec<u8>,
+    sink: Sink<'a>,
+    /// Set once the lines can not be taken anymore, the rest of the output is discarded.
+    closed: bool,
+    /// The first error of `Sink::Writer`.
+    error: Option<std::io::Error>,
+}
+
+/// Where the lines of `OutputBuffer` go.
+enum Sink<'a> {
+    /// The lines are kept, see `IPSet::capture`.
+    Lines(Vec<String>),
+    /// The lines are sent, see `IPSet::stream_list`.
+    Sender(SyncSender<Result<String, Error>>),
+    /// The lines are written, see `IPSet::write_output`.
+    Writer(&'a mut dyn Write),
+}
+
+impl Default for Sink<'_> {
+    fn default() -> Self {
+        Sink::Lines(vec![])
+    }
+}
+
+impl<'a> OutputBuffer<'a> {
+    fn new(sink: Sink<'a>) -> Self {
+        Self {
+            sink,
+            ..Default::default()
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for chunk in data.split_inclusive(|c| *c == b'\n') {
+            match chunk.strip_suffix(b"\n") {
+                Some(line) => {
+                    self.pending.extend_from_slice(line);
+                    self.end_line();
+                }
+                None => self.pending.extend_from_slice(chunk),
+            }
+        }
+    }
+
+    /// Move the pending text into the sink, the empty lines are skipped.
+    fn end_line(&mut self) {
+        if !self.pending.is_empty() {
+            let line = String::from_utf8_lossy(&self.pending).into_owned();
+            self.pending.clear();
+            match &mut self.sink {
+                _ if self.closed => {}
+                Sink::Lines(lines) => lines.push(line),
+                Sink::Sender(sender) => self.closed = sender.send(Ok(line)).is_err(),
+                Sink::Writer(writer) => {
+                    if let Err(err) = writeln!(writer, "{}", line) {
+                        self.error = Some(err);
+                        self.closed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn into_lines(mut self) -> Vec<String> {
+        self.end_line();
+        match self.sink {
+            Sink::Lines(lines) => lines,
+            _ => vec![],
+        }
+    }
+}
+
+/// Wrapper for ipset instance in c. The reports of libipset on successful commands are kept
+/// as warnings, which can be taken by `take_warnings`.
 pub struct IPSet {
     pub(crate) set: *mut binding::ipset,
     pub(crate) session: *mut binding::ipset_session,
+    warnings: RefCell<Vec<String>>,
+}
+
+impl Default for IPSet {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IPSet {
@@ -16,10 +164,184 @@ impl IPSet {
             binding::ipset_load_types();
             let set = binding::ipset_init();
             let session = binding::ipset_session(set);
-            IPSet { set, session }
+            let ipset = IPSet {
+                set,
+                session,
+                warnings: Default::default(),
+            };
+            ipset.custom_printf(None, std::ptr::null_mut());
+            ipset
+        }
+    }
+
+    /// Install `outfn` as the output function of libipset. The error functions are always
+    /// replaced too, as the default ones of libipset exit the process on errors.
+    pub(crate) fn custom_printf(
+        &self,
+        outfn: binding::ipset_print_outfn,
+        p: *mut std::os::raw::c_void,
+    ) {
+        unsafe {
+            binding::ipset_custom_printf(
+                self.set,
+                Some(binding::custom_error),
+                Some(binding::standard_error),
+                outfn,
+                p,
+            );
         }
     }
 
+    /// Run `f` with the output of libipset printed in `mode` and captured, return the printed lines.
+    pub(crate) fn capture<F>(
+        &self,
+        mode: binding::ipset_output_mode,
+        f: F,
+    ) -> Result<Vec<String>, Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        let mut output = OutputBuffer::default();
+        self.output(mode, &mut output, f)?;
+        Ok(output.into_lines())
+    }
+
+    /// List the set `name` with the lines sent to `sender` as soon as they are printed,
+    /// so the output is never kept as a whole. The sender blocks libipset if the channel is full.
+    pub(crate) fn stream_list(
+        &self,
+        name: &CStr,
+        sender: SyncSender<Result<String, Error>>,
+    ) -> Result<(), Error> {
+        let mut output = OutputBuffer::new(Sink::Sender(sender));
+        let ret = self.output(
+            binding::ipset_output_mode_IPSET_LIST_PLAIN,
+            &mut output,
+            || {
+                self.set_data(binding::ipset_opt_IPSET_SETNAME, name.as_ptr() as _)?;
+                self.run_cmd(binding::ipset_cmd_IPSET_CMD_LIST)
+            },
+        );
+        output.end_line();
+        ret
+    }
+
+    /// Run `f` with the output of libipset printed in `mode` and written to `writer` line by line,
+    /// so the output is never kept as a whole. The first error of `writer` aborts the command
+    /// and is returned.
+    pub(crate) fn write_output<F>(
+        &self,
+        mode: binding::ipset_output_mode,
+        mut writer: impl Write,
+        f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        let mut output = OutputBuffer::new(Sink::Writer(&mut writer));
+        let ret = self.output(mode, &mut output, f);
+        output.end_line();
+        if let Some(err) = output.error {
+            return Err(err.into());
+        }
+        ret?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Run `f` with the output of libipset printed in `mode` into `output`.
+    fn output<F>(
+        &self,
+        mode: binding::ipset_output_mode,
+        output: &mut OutputBuffer,
+        f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        self.custom_printf(Some(binding::print_out), output as *mut _ as _);
+        let ret = unsafe {
+            if binding::ipset_session_output(self.session, mode) < 0 {
+                let (message, error) = self.take_error();
+                Err(Error::Cmd(message, error))
+            } else {
+                f()
+            }
+        };
+        unsafe {
+            binding::ipset_session_output(self.session, binding::ipset_output_mode_IPSET_LIST_NONE);
+        }
+        self.custom_printf(None, std::ptr::null_mut());
+        ret
+    }
+
+    /// List the names and headers of all the sets in the host.
+    pub fn sets(&self) -> Result<Vec<SetInfo>, Error> {
+        let option = EnvOption::ListHeader.to_option();
+        let ret = unsafe {
+            let list_header = binding::ipset_envopt_test(self.session, option);
+            binding::ipset_envopt_set(self.session, option);
+            binding::ipset_data_reset(binding::ipset_session_data(self.session));
+            let ret = self.capture(binding::ipset_output_mode_IPSET_LIST_PLAIN, || {
+                self.run_cmd(binding::ipset_cmd_IPSET_CMD_LIST)
+            });
+            if !list_header {
+                binding::ipset_envopt_unset(self.session, option);
+            }
+            ret
+        };
+        let mut sets: Vec<SetInfo> = vec![];
+        for line in ret? {
+            if line.starts_with("Name:") {
+                sets.push(SetInfo::default());
+            }
+            sets.last_mut()
+                .ok_or_else(|| Error::InvalidOutput(line.clone()))?
+                .update_from_str(&line)?;
+        }
+        Ok(sets)
+    }
+
+    /// Run a single command in the `ipset restore` format, like `create foo hash:ip`.
+    pub(crate) fn parse_line(&self, line: &str) -> Result<(), Error> {
+        let mut line = CString::new(line)?.into_bytes_with_nul();
+        let ret = unsafe { binding::ipset_parse_line(self.set, line.as_mut_ptr() as _) };
+        self.check(ret)
+    }
+
+    /// Convert the return value of libipset command `cmd` to result, the report of a successful
+    /// command is kept as a warning.
+    fn check(&self, ret: std::os::raw::c_int) -> Result<(), Error> {
+        if ret < 0 {
+            let (message, error) = self.take_error();
+            Err(Error::Cmd(message, error))
+        } else {
+            self.keep_warning();
+            Ok(())
+        }
+    }
+
+    /// Move the report message of libipset, if any, into the warnings.
+    pub(crate) fn keep_warning(&self) {
+        let typ = unsafe { binding::ipset_session_report_type(self.session) };
+        if typ != binding::ipset_err_type_IPSET_NO_ERROR {
+            let (message, _) = self.error();
+            self.warn(&message);
+        }
+    }
+
+    /// Keep `message` in the warnings, unless it is empty.
+    pub(crate) fn warn(&self, message: &str) {
+        if !message.trim().is_empty() {
+            self.warnings.borrow_mut().push(message.trim().to_string());
+        }
+    }
+
+    /// Take the warnings reported by the successful commands since the last call.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.take()
+    }
+
     /// get the error message and type.
     pub(crate) fn error(&self) -> (String, binding::ipset_err_type) {
         unsafe {
@@ -31,10 +353,272 @@ impl IPSet {
         }
     }
 
-    /// Restore a set from a file.
+    /// Take the error message of libipset with whether it is an error. The codes are learned
+    /// for the set type of the session on the first error, see `Error::code`.
+    pub(crate) fn take_error(&self) -> (String, bool) {
+        let (message, typ) = self.error();
+        let error = typ == binding::ipset_err_type_IPSET_ERROR;
+        if error {
+            self.learn_error_codes();
+        }
+        (message, error)
+    }
+
+    /// Get the error `errno` of the kernel for `cmd`, with the message libipset prints for it.
+    pub(crate) fn kernel_error(&self, cmd: binding::ipset_cmd, errno: i32) -> Error {
+        unsafe {
+            binding::ipset_errcode(self.session, cmd, errno);
+        }
+        let (message, error) = self.take_error();
+        Error::Cmd(message, error)
+    }
+
+    /// Render the messages of all the codes with `ipset_errcode` for the set type saved in the
+    /// session, so the code of an error is found by its message. libipset does not expose the
+    /// code of the netlink error it receives, and the messages of the type specific codes depend
+    /// on the set type, so this is done once for each set type.
+    fn learn_error_codes(&self) {
+        let typename = unsafe {
+            let typename = binding::saved_typename(self.session);
+            if typename.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(typename).to_string_lossy().into_owned())
+            }
+        };
+        let mut codes = error_codes();
+        if !codes.typenames.insert(typename.clone()) {
+            return;
+        }
+        let method = typename
+            .and_then(|typename| SetTypeDescriptor::parse(&typename).ok())
+            .map(|desc| desc.method);
+        let private = binding::ipset_errno_IPSET_ERR_PRIVATE as i32;
+        let specific = binding::ipset_errno_IPSET_ERR_TYPE_SPECIFIC as i32;
+        // The codes of ipset go first, as the messages of the errno are generic ones.
+        let codes_of_ipset = (private..private + 64).chain(specific..specific + 64);
+        for errno in codes_of_ipset.chain(1..256) {
+            for cmd in binding::ipset_cmd_IPSET_CMD_NONE..binding::ipset_cmd_IPSET_MSG_MAX {
+                unsafe {
+                    binding::ipset_errcode(self.session, cmd, errno);
+                }
+                let (message, _) = self.error();
+                let code = ErrorCode {
+                    errno,
+                    cmd,
+                    method: method.filter(|_| errno >= specific),
+                };
+                codes.insert(&message, code);
+            }
+        }
+    }
+
+    /// Set `opt` in the data of the session.
+    fn set_data(
+        &self,
+        opt: binding::ipset_opt,
+        value: *const std::ffi::c_void,
+    ) -> Result<(), Error> {
+        unsafe {
+            let data = binding::ipset_session_data(self.session);
+            if binding::ipset_data_set(data, opt, value) < 0 {
+                let (message, typ) = self.error();
+                Err(Error::DataSet(
+                    message,
+                    typ == binding::ipset_err_type_IPSET_ERROR,
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Run `cmd` with the data of the session.
+    fn run_cmd(&self, cmd: binding::ipset_cmd) -> Result<(), Error> {
+        self.run_cmd_at(cmd, 0)
+    }
+
+    /// Run `cmd` as line `lineno` of a restore, add/del commands are buffered by libipset
+    /// until `commit` if `lineno` is not zero.
+    pub(crate) fn run_cmd_at(&self, cmd: binding::ipset_cmd, lineno: u32) -> Result<(), Error> {
+        let ret = unsafe { binding::ipset_cmd(self.session, cmd, lineno) };
+        self.check(ret)
+    }
+
+    /// Get the protocol versions of libipset and the kernel. libipset keeps the versions of the
+    /// kernel to itself, so `IPSET_CMD_PROTOCOL` is sent on a netlink socket of its own, an error
+    /// is returned if the kernel can not be reached.
+    pub fn version() -> Result<Version, Error> {
+        let library = (
+            binding::IPSET_PROTOCOL_MIN as u8,
+            binding::IPSET_PROTOCOL as u8,
+        );
+        let kernel = netlink::protocol(&netlink::Socket::open()?)?;
+        Ok(Version { library, kernel })
+    }
+
+    /// Get the revisions supported by the kernel for `typename`, like `hash:net`, with `IPSET_CMD_TYPE`.
+    pub fn type_revisions(&self, typename: &str) -> Result<TypeRevision, Error> {
+        let typename = CString::new(typename)?;
+        unsafe {
+            let data = binding::ipset_session_data(self.session);
+            binding::ipset_data_reset(data);
+            let ret = self
+                .set_data(
+                    binding::ipset_opt_IPSET_OPT_TYPENAME,
+                    typename.as_ptr() as _,
+                )
+                .and_then(|_| {
+                    self.set_data(
+                        binding::ipset_opt_IPSET_OPT_FAMILY,
+                        &binding::NFPROTO_IPV4 as *const _ as _,
+                    )
+                })
+                .and_then(|_| self.run_cmd(binding::ipset_cmd_IPSET_CMD_TYPE))
+                .and_then(|_| {
+                    let max = binding::ipset_data_get(data, binding::ipset_opt_IPSET_OPT_REVISION)
+                        as *const u8;
+                    let min =
+                        binding::ipset_data_get(data, binding::ipset_opt_IPSET_OPT_REVISION_MIN)
+                            as *const u8;
+                    if max.is_null() {
+                        Err(Error::InvalidOutput("revision not found in type".into()))
+                    } else {
+                        // Old kernels do not report the minimal revision.
+                        let min = if min.is_null() { *max } else { *min };
+                        Ok(TypeRevision { min, max: *max })
+                    }
+                });
+            binding::ipset_data_reset(data);
+            ret
+        }
+    }
+
+    /// Save all the sets in the host to `writer` in the `ipset save` format.
+    pub fn save_all(&self, writer: impl Write) -> Result<(), Error> {
+        self.write_output(
+            binding::ipset_output_mode_IPSET_LIST_SAVE,
+            writer,
+            || unsafe {
+                binding::ipset_data_reset(binding::ipset_session_data(self.session));
+                self.run_cmd(binding::ipset_cmd_IPSET_CMD_SAVE)
+            },
+        )
+    }
+
+    /// Send all the commands buffered by libipset in restore mode to the kernel.
+    pub(crate) fn commit(&self) -> Result<(), Error> {
+        let ret = unsafe { binding::ipset_commit(self.session) };
+        self.check(ret)
+    }
+
+    /// Restore sets from `reader` in the `ipset restore` format, the failed line is reported
+    /// with `Error::RestoreLine`.
+    pub fn restore_from_reader(&self, reader: impl Read) -> Result<(), Error> {
+        let ret = self.restore_lines(BufReader::new(reader));
+        unsafe {
+            binding::ipset_session_lineno(self.session, 0);
+        }
+        ret
+    }
+
+    /// Restore sets from `s` in the `ipset restore` format, see `restore_from_reader`.
+    pub fn restore_from_str(&self, s: &str) -> Result<(), Error> {
+        self.restore_from_reader(s.as_bytes())
+    }
+
+    /// Parse all the lines from `reader` like `ipset_parse_stream`. The lines since the last
+    /// commit are kept for error reporting, as the errors of buffered commands are reported
+    /// on commit.
+    fn restore_lines(&self, reader: impl BufRead) -> Result<(), Error> {
+        let mut batch = RestoreBatch::default();
+        let mut lineno = 0;
+        for line in reader.lines() {
+            let line = line?;
+            lineno += 1;
+            let text = line.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let commit = text == "COMMIT";
+            batch.push(lineno, line);
+            let ret = if commit {
+                self.commit()
+            } else {
+                unsafe {
+                    binding::ipset_session_lineno(self.session, lineno as u32);
+                }
+                self.parse_line(batch.line(lineno).unwrap_or_default().trim())
+            };
+            ret.map_err(|err| batch.error(err, lineno))?;
+            if commit {
+                batch.clear();
+            }
+        }
+        self.commit().map_err(|err| batch.error(err, lineno))
+    }
+
+    /// Restore sets from `reader` in the `ipset restore` format, and continue past the failed
+    /// lines. Every line is committed on its own to find out exactly which lines are applied,
+    /// so it is slower than `restore_from_reader` for large inputs. With `exist`, creating an
+    /// existing set, adding an existing entry or deleting a missing entry are not failures.
+    pub fn restore_with_report(
+        &self,
+        reader: impl Read,
+        exist: bool,
+    ) -> Result<RestoreReport, Error> {
+        let option = EnvOption::Exist.to_option();
+        let was_exist = unsafe { binding::ipset_envopt_test(self.session, option) };
+        if exist {
+            unsafe {
+                binding::ipset_envopt_set(self.session, option);
+            }
+        }
+        let mut report = RestoreReport::default();
+        let ret = self.restore_each(BufReader::new(reader), &mut report);
+        unsafe {
+            binding::ipset_session_lineno(self.session, 0);
+            if exist && !was_exist {
+                binding::ipset_envopt_unset(self.session, option);
+            }
+        }
+        ret.map(|_| report)
+    }
+
+    /// Parse and commit all the lines from `reader` one by one, the outcomes are kept in `report`.
+    fn restore_each(&self, reader: impl BufRead, report: &mut RestoreReport) -> Result<(), Error> {
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let text = line.trim();
+            if text.is_empty() || text.starts_with('#') || text == "COMMIT" {
+                continue;
+            }
+            unsafe {
+                binding::ipset_session_lineno(self.session, index as u32 + 1);
+            }
+            match self.parse_line(text).and_then(|_| self.commit()) {
+                // Warnings, like a missed test, do not fail the line.
+                Ok(_) | Err(Error::Cmd(_, false)) => report.applied.push(index + 1),
+                Err(err) => report.failures.push(RestoreFailure {
+                    line: index + 1,
+                    text: text.to_string(),
+                    error: match err {
+                        Error::Cmd(message, error) => match split_line_error(&message) {
+                            Some((_, message)) => Error::Cmd(message.to_string(), error),
+                            None => Error::Cmd(message, error),
+                        },
+                        err => err,
+                    },
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore a set from a file, see `restore_with_report` for the failed lines.
     pub fn restore(&self, filename: String) -> Result<(), Error> {
         unsafe {
-            let filename = std::ffi::CString::new(filename).unwrap();
+            let filename = CString::new(filename).unwrap();
             let ret = binding::ipset_session_io_normal(
                 self.session,
                 filename.as_ptr(),
@@ -58,6 +642,54 @@ impl IPSet {
     }
 }
 
+/// The lines of a restore since the last commit.
+#[derive(Default)]
+struct RestoreBatch {
+    /// The number of the first line in `lines`.
+    first: usize,
+    lines: Vec<String>,
+}
+
+impl RestoreBatch {
+    fn push(&mut self, lineno: usize, line: String) {
+        if self.lines.is_empty() {
+            self.first = lineno;
+        }
+        self.lines.push(line);
+    }
+
+    /// Get the text of line `lineno`, `None` if not in the batch.
+    fn line(&self, lineno: usize) -> Option<&str> {
+        lineno
+            .checked_sub(self.first)
+            .and_then(|index| self.lines.get(index))
+            .map(String::as_str)
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// Convert `err` of the batch to `Error::RestoreLine`, the line is parsed from the message,
+    /// or `lineno` is used if not found in the batch.
+    fn error(&self, err: Error, lineno: usize) -> Error {
+        match err {
+            Error::Cmd(message, ..) => {
+                let (line, message) = match split_line_error(&message) {
+                    Some((line, message)) if self.line(line).is_some() => (line, message),
+                    _ => (lineno, message.as_str()),
+                };
+                Error::RestoreLine {
+                    line,
+                    text: self.line(line).unwrap_or_default().to_string(),
+                    message: message.to_string(),
+                }
+            }
+            err => err,
+        }
+    }
+}
+
 impl Drop for IPSet {
     fn drop(&mut self) {
         unsafe {
@@ -65,3 +697,183 @@ impl Drop for IPSet {
         }
     }
 }
+
+#[allow(unused_imports)]
+mod tests {
+    use std::ffi::CString;
+    use std::sync::mpsc::sync_channel;
+
+    use crate::binding;
+    use crate::set::{error_codes, OutputBuffer, RestoreBatch, Sink};
+    use crate::types::{Error, ErrorCode, ErrorKind, SetMethod};
+
+    #[test]
+    fn test_output_buffer() {
+        let mut output = OutputBuffer::default();
+        output.push(b"Name: test\nType: ha");
+        output.push(b"sh:ip\n\n");
+        output.push(b"Members:\n10.0.0.1 comment \"\xc3");
+        output.push(b"\xa9\"");
+        assert_eq!(
+            output.into_lines(),
+            vec![
+                "Name: test",
+                "Type: hash:ip",
+                "Members:",
+                "10.0.0.1 comment \"é\""
+            ]
+        );
+
+        let (sender, receiver) = sync_channel(1);
+        let mut output = OutputBuffer::new(Sink::Sender(sender));
+        output.push(b"Members:\n10.0.0");
+        assert_eq!(receiver.try_recv().unwrap().unwrap(), "Members:");
+        assert!(receiver.try_recv().is_err());
+        output.push(b".1\n");
+        assert_eq!(receiver.try_recv().unwrap().unwrap(), "10.0.0.1");
+        drop(receiver);
+        output.push(b"10.0.0.2\n");
+        assert!(output.closed);
+        assert!(output.into_lines().is_empty());
+    }
+
+    #[test]
+    fn test_print_out() {
+        let member = "a".repeat(4096);
+        let comment = "b".repeat(1023);
+        let mut output = OutputBuffer::default();
+        let p = &mut output as *mut OutputBuffer as _;
+        let fmt = CString::new("%s").unwrap();
+        let comment_fmt = CString::new(" comment \"%s\"\n").unwrap();
+        for _ in 0..2 {
+            let member = CString::new(member.as_str()).unwrap();
+            let comment = CString::new(comment.as_str()).unwrap();
+            unsafe {
+                let n = binding::print_out(std::ptr::null_mut(), p, fmt.as_ptr(), member.as_ptr());
+                assert_eq!(n, 4096);
+                binding::print_out(
+                    std::ptr::null_mut(),
+                    p,
+                    comment_fmt.as_ptr(),
+                    comment.as_ptr(),
+                );
+            }
+        }
+        let line = format!("{} comment \"{}\"", member, comment);
+        assert_eq!(output.into_lines(), vec![line.clone(), line]);
+
+        let (sender, receiver) = sync_channel(1);
+        let mut output = OutputBuffer::new(Sink::Sender(sender));
+        let p = &mut output as *mut OutputBuffer as _;
+        let fmt = CString::new("10.0.0.1\n").unwrap();
+        unsafe {
+            assert_eq!(binding::print_out(std::ptr::null_mut(), p, fmt.as_ptr()), 9);
+            drop(receiver);
+            assert_eq!(
+                binding::print_out(std::ptr::null_mut(), p, fmt.as_ptr()),
+                -1
+            );
+        }
+    }
+
+    #[test]
+    fn test_output_writer() {
+        let mut saved = vec![];
+        let mut output = OutputBuffer::new(Sink::Writer(&mut saved));
+        output.push(b"create test hash:ip\nadd test 10.0");
+        output.push(b".0.1\n");
+        assert!(output.into_lines().is_empty());
+        assert_eq!(saved, b"create test hash:ip\nadd test 10.0.0.1\n");
+
+        /// A writer taking `0` bytes at most, like a full disk.
+        struct Full(usize);
+
+        impl std::io::Write for Full {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                let n = buf.len().min(self.0);
+                self.0 -= n;
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = Full(20);
+        let mut output = OutputBuffer::new(Sink::Writer(&mut writer));
+        let p = &mut output as *mut OutputBuffer as _;
+        let fmt = CString::new("create test hash:ip\n").unwrap();
+        unsafe {
+            assert_eq!(
+                binding::print_out(std::ptr::null_mut(), p, fmt.as_ptr()),
+                20
+            );
+            assert_eq!(
+                binding::print_out(std::ptr::null_mut(), p, fmt.as_ptr()),
+                -1
+            );
+        }
+        assert_eq!(
+            output.error.map(|err| err.kind()),
+            Some(std::io::ErrorKind::WriteZero)
+        );
+    }
+
+    #[test]
+    fn test_restore_batch() {
+        let mut batch = RestoreBatch::default();
+        batch.push(3, "add test 10.0.0.1".into());
+        batch.push(5, "add test 10.0.0.2".into());
+        batch.clear();
+        batch.push(7, "add test 10.0.0.3".into());
+        batch.push(8, "add test 10.0.0.4".into());
+        assert_eq!(batch.line(3), None);
+        assert_eq!(batch.line(8), Some("add test 10.0.0.4"));
+        let err = |message: &str| Error::Cmd(message.into(), true);
+        assert!(matches!(
+            batch.error(err("Error in line 7: Element cannot be added"), 8),
+            Error::RestoreLine { line: 7, text, message }
+                if text == "add test 10.0.0.3" && message == "Element cannot be added"
+        ));
+        // a line before the batch is not known anymore.
+        assert!(matches!(
+            batch.error(err("Error in line 3: failed"), 8),
+            Error::RestoreLine { line: 8, text, .. } if text == "add test 10.0.0.4"
+        ));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let exist = binding::ipset_errno_IPSET_ERR_EXIST as i32;
+        let add = ErrorCode {
+            errno: exist,
+            cmd: binding::ipset_cmd_IPSET_CMD_ADD,
+            method: None,
+        };
+        let hash_full = ErrorCode {
+            errno: binding::ipset_errno_IPSET_ERR_TYPE_SPECIFIC as i32,
+            cmd: binding::ipset_cmd_IPSET_CMD_ADD,
+            method: Some(SetMethod::Hash),
+        };
+        let message = "Element cannot be added to the set: it's already added";
+        {
+            let mut codes = error_codes();
+            codes.insert(message, add);
+            // the first code rendered for a message is kept.
+            codes.insert(message, hash_full);
+            codes.insert("Hash is full, cannot add more elements", hash_full);
+        }
+        let err = Error::Cmd(message.into(), true);
+        assert_eq!(err.code(), Some(add));
+        assert_eq!(err.kind(), ErrorKind::AlreadyAdded);
+        // the line number of a restore is ignored.
+        let err = Error::Cmd(format!("Error in line 3: {}", message), true);
+        assert_eq!(err.kind(), ErrorKind::AlreadyAdded);
+        let err = Error::TypeGet("Hash is full, cannot add more elements".into(), true);
+        assert_eq!(err.kind(), ErrorKind::HashFull);
+    }
+}
@@ -1,15 +1,20 @@
 //! All the types used by libipset.
 
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::ffi::{CString, NulError};
 use std::fmt::Formatter;
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::ParseIntError;
+use std::str::FromStr;
 
 use derive_more::{Display, From, Into};
 use ipset_derive::SetType;
 
-use crate::{binding, Session};
+#[cfg(feature = "serde")]
+pub use crate::serialize::Entry;
+use crate::set::error_code;
+use crate::{binding, DynSession, Session};
 
 /// list method
 pub struct ListMethod;
@@ -135,7 +140,7 @@ impl Display for IpDataType {
 }
 
 /// net data type
-#[derive(Default, From, Into)]
+#[derive(Default, Clone, From, Into)]
 pub struct NetDataType {
     ip: IpDataType,
     cidr: u8,
@@ -194,7 +199,7 @@ impl Display for NetDataType {
 }
 
 /// mac data type, [u8; 6]
-#[derive(Default, From, Into)]
+#[derive(Default, Clone, From, Into)]
 pub struct MacDataType {
     mac: [u8; 6],
 }
@@ -227,14 +232,55 @@ impl Display for MacDataType {
     }
 }
 
-/// port data type, u16
-#[derive(Default, From, Into)]
+/// port data type, u16 with an optional protocol, like `tcp:80`.
+#[derive(Default, Clone)]
 pub struct PortDataType {
     port: u16,
+    proto: Option<u8>,
+}
+
+/// Names of the protocols with ports, as printed by libipset.
+const PROTOCOLS: &[(&str, u8)] = &[("tcp", 6), ("udp", 17), ("sctp", 132), ("udplite", 136)];
+
+impl PortDataType {
+    /// create port with protocol number `proto`, like 6 for tcp.
+    pub fn with_proto(port: u16, proto: u8) -> Self {
+        Self {
+            port,
+            proto: Some(proto),
+        }
+    }
+
+    /// return port number
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// return protocol number if specified. The kernel has no default for the hash types with
+    /// ports, so the netlink requests of `Session::test_many` and `Session::add_many` use tcp
+    /// when it is not specified, as the ipset command does.
+    pub fn proto(&self) -> Option<u8> {
+        self.proto
+    }
+}
+
+impl From<u16> for PortDataType {
+    fn from(port: u16) -> Self {
+        Self { port, proto: None }
+    }
+}
+
+impl From<PortDataType> for u16 {
+    fn from(value: PortDataType) -> Self {
+        value.port
+    }
 }
 
 impl<T: SetType> SetData<T> for PortDataType {
     fn set_data(&self, session: &Session<T>, from: Option<bool>) -> Result<(), Error> {
+        if let Some(proto) = &self.proto {
+            session.set_data(binding::ipset_opt_IPSET_OPT_PROTO, proto as *const _ as _)?;
+        }
         let opt = match from {
             Some(true) => binding::ipset_opt_IPSET_OPT_PORT_FROM,
             Some(false) => binding::ipset_opt_IPSET_OPT_PORT_TO,
@@ -246,19 +292,34 @@ impl<T: SetType> SetData<T> for PortDataType {
 
 impl Parse for PortDataType {
     fn parse(&mut self, s: &str) -> Result<(), Error> {
-        self.port = s.parse()?;
+        if let Some((proto, port)) = s.split_once(':') {
+            self.proto = match PROTOCOLS.iter().find(|(name, _)| *name == proto) {
+                Some((_, number)) => Some(*number),
+                None => Some(proto.parse()?),
+            };
+            self.port = port.parse()?;
+        } else {
+            self.proto = None;
+            self.port = s.parse()?;
+        }
         Ok(())
     }
 }
 
 impl Display for PortDataType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.port)
+        match self.proto {
+            Some(proto) => match PROTOCOLS.iter().find(|(_, number)| *number == proto) {
+                Some((name, _)) => write!(f, "{}:{}", name, self.port),
+                None => write!(f, "{}:{}", proto, self.port),
+            },
+            None => write!(f, "{}", self.port),
+        }
     }
 }
 
 /// iface data type, CString
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct IfaceDataType {
     name: CString,
 }
@@ -297,7 +358,7 @@ impl Display for IfaceDataType {
 }
 
 /// mark data type, u32
-#[derive(Default, From, Into)]
+#[derive(Default, Clone, From, Into)]
 pub struct MarkDataType {
     mark: u32,
 }
@@ -325,7 +386,7 @@ impl Display for MarkDataType {
 }
 
 /// set name, CString
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct SetDataType {
     name: CString,
 }
@@ -363,6 +424,146 @@ impl Display for SetDataType {
     }
 }
 
+/// A data type only known at runtime, see `DynEntry`.
+#[derive(Clone)]
+pub enum DynDataType {
+    Ip(IpDataType),
+    Net(NetDataType),
+    Mac(MacDataType),
+    Port(PortDataType),
+    Iface(IfaceDataType),
+    Mark(MarkDataType),
+    Set(SetDataType),
+}
+
+macro_rules! impl_dyn_data_type {
+    ($($variant:ident($ty:ty)),+) => {
+        impl DynDataType {
+            /// Create a default value of the data type `kind`.
+            fn from_kind(kind: &DataKind) -> Self {
+                match kind {
+                    $(DataKind::$variant => DynDataType::$variant(Default::default()),)+
+                }
+            }
+        }
+
+        impl<T: SetType> SetData<T> for DynDataType {
+            fn set_data(&self, session: &Session<T>, from: Option<bool>) -> Result<(), Error> {
+                match self {
+                    $(DynDataType::$variant(data) => data.set_data(session, from),)+
+                }
+            }
+        }
+
+        impl Parse for DynDataType {
+            fn parse(&mut self, s: &str) -> Result<(), Error> {
+                match self {
+                    $(DynDataType::$variant(data) => data.parse(s),)+
+                }
+            }
+        }
+
+        impl Display for DynDataType {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $(DynDataType::$variant(data) => data.fmt(f),)+
+                }
+            }
+        }
+
+        $(
+            impl From<$ty> for DynDataType {
+                fn from(data: $ty) -> Self {
+                    DynDataType::$variant(data)
+                }
+            }
+        )+
+    };
+}
+
+impl_dyn_data_type!(
+    Ip(IpDataType),
+    Net(NetDataType),
+    Mac(MacDataType),
+    Port(PortDataType),
+    Iface(IfaceDataType),
+    Mark(MarkDataType),
+    Set(SetDataType)
+);
+
+/// An entry of a set whose type is only known at runtime, the data types are in the same order
+/// as the typename, e.g. `[Net, Port]` for `hash:net,port`.
+#[derive(Default, Clone)]
+pub struct DynEntry(Vec<DynDataType>);
+
+impl DynEntry {
+    /// Create an entry with default values for the data types of `typename`, like `hash:net,port`.
+    /// The entry can be used as a template for parsing.
+    pub fn template(typename: &str) -> Result<Self, Error> {
+        let desc = SetTypeDescriptor::parse(typename)?;
+        Ok(DynEntry(
+            desc.data_types.iter().map(DynDataType::from_kind).collect(),
+        ))
+    }
+
+    /// Test if the data types of the entry are the same as `other`, the values are ignored.
+    pub fn same_type(&self, other: &DynEntry) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .all(|(a, b)| std::mem::discriminant(a) == std::mem::discriminant(b))
+    }
+
+    /// The data of the entry.
+    pub fn data(&self) -> &[DynDataType] {
+        &self.0
+    }
+}
+
+impl From<Vec<DynDataType>> for DynEntry {
+    fn from(data: Vec<DynDataType>) -> Self {
+        DynEntry(data)
+    }
+}
+
+impl From<DynDataType> for DynEntry {
+    fn from(data: DynDataType) -> Self {
+        DynEntry(vec![data])
+    }
+}
+
+impl<T: SetType> SetData<T> for DynEntry {
+    fn set_data(&self, session: &Session<T>, from: Option<bool>) -> Result<(), Error> {
+        for data in &self.0 {
+            data.set_data(session, from)?;
+        }
+        Ok(())
+    }
+}
+
+impl Parse for DynEntry {
+    /// Parse `s` into the data types of the entry, so the entry must be created by `template`.
+    fn parse(&mut self, s: &str) -> Result<(), Error> {
+        let items: Vec<_> = s.split(',').collect();
+        if self.0.is_empty() || items.len() != self.0.len() {
+            return Err(Error::DataParse(s.into()));
+        }
+        for (data, item) in self.0.iter_mut().zip(items) {
+            data.parse(item)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for DynEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let data: Vec<_> = self.0.iter().map(|data| data.to_string()).collect();
+        write!(f, "{}", data.join(","))
+    }
+}
+
 macro_rules! impl_name {
     ($($types:ident),+) => {
         impl<$($types,)+> TypeName for ($($types),+)
@@ -477,6 +678,325 @@ where
     }
 }
 
+/// Storage method of a set type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SetMethod {
+    Bitmap,
+    Hash,
+    List,
+}
+
+/// Kind of a data type component of a set type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataKind {
+    Ip,
+    Net,
+    Mac,
+    Port,
+    Iface,
+    Mark,
+    Set,
+}
+
+/// Description of a set type supported by ipset, parsed from a typename with
+/// `SetTypeDescriptor::parse`.
+#[derive(Debug)]
+pub struct SetTypeDescriptor {
+    /// The canonical typename, like `hash:net,port`.
+    pub typename: &'static str,
+    /// The legacy names accepted by ipset, like `nethash`.
+    pub aliases: &'static [&'static str],
+    pub method: SetMethod,
+    /// The data types in the order of the typename.
+    pub data_types: &'static [DataKind],
+    /// Revisions of the set type which add support for options, as documented in the kernel.
+    /// The options not listed here are supported since the first revision.
+    revisions: &'static [(&'static str, u8)],
+}
+
+/// All the set types supported by ipset.
+const SET_TYPES: &[SetTypeDescriptor] = &[
+    SetTypeDescriptor {
+        typename: "bitmap:ip",
+        aliases: &["ipmap"],
+        method: SetMethod::Bitmap,
+        data_types: &[DataKind::Ip],
+        revisions: &[("counters", 1), ("comment", 2), ("skbinfo", 3)],
+    },
+    SetTypeDescriptor {
+        typename: "bitmap:ip,mac",
+        aliases: &["macipmap"],
+        method: SetMethod::Bitmap,
+        data_types: &[DataKind::Ip, DataKind::Mac],
+        revisions: &[("counters", 1), ("comment", 2), ("skbinfo", 3)],
+    },
+    SetTypeDescriptor {
+        typename: "bitmap:port",
+        aliases: &["portmap"],
+        method: SetMethod::Bitmap,
+        data_types: &[DataKind::Port],
+        revisions: &[("counters", 1), ("comment", 2), ("skbinfo", 3)],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip",
+        aliases: &["iphash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip],
+        revisions: &[
+            ("counters", 1),
+            ("comment", 2),
+            ("forceadd", 3),
+            ("skbinfo", 4),
+            ("bucketsize", 5),
+            ("initval", 5),
+            ("bitmask", 6),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:mac",
+        aliases: &[],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Mac],
+        revisions: &[("bucketsize", 1), ("initval", 1)],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip,mac",
+        aliases: &[],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip, DataKind::Mac],
+        revisions: &[("bucketsize", 1), ("initval", 1)],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip,mark",
+        aliases: &[],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip, DataKind::Mark],
+        revisions: &[
+            ("forceadd", 1),
+            ("skbinfo", 2),
+            ("bucketsize", 3),
+            ("initval", 3),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip,port",
+        aliases: &["ipporthash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip, DataKind::Port],
+        revisions: &[
+            ("counters", 2),
+            ("comment", 3),
+            ("forceadd", 4),
+            ("skbinfo", 5),
+            ("bucketsize", 6),
+            ("initval", 6),
+            ("netmask", 7),
+            ("bitmask", 7),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip,port,ip",
+        aliases: &["ipportiphash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip, DataKind::Port, DataKind::Ip],
+        revisions: &[
+            ("counters", 2),
+            ("comment", 3),
+            ("forceadd", 4),
+            ("skbinfo", 5),
+            ("bucketsize", 6),
+            ("initval", 6),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:ip,port,net",
+        aliases: &["ipportnethash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Ip, DataKind::Port, DataKind::Net],
+        revisions: &[
+            ("nomatch", 3),
+            ("counters", 4),
+            ("comment", 5),
+            ("forceadd", 6),
+            ("skbinfo", 7),
+            ("bucketsize", 8),
+            ("initval", 8),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:net",
+        aliases: &["nethash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Net],
+        revisions: &[
+            ("nomatch", 2),
+            ("counters", 3),
+            ("comment", 4),
+            ("forceadd", 5),
+            ("skbinfo", 6),
+            ("bucketsize", 7),
+            ("initval", 7),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:net,net",
+        aliases: &[],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Net, DataKind::Net],
+        revisions: &[
+            ("forceadd", 1),
+            ("skbinfo", 2),
+            ("bucketsize", 3),
+            ("initval", 3),
+            ("netmask", 4),
+            ("bitmask", 4),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:net,port",
+        aliases: &["netporthash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Net, DataKind::Port],
+        revisions: &[
+            ("nomatch", 3),
+            ("counters", 4),
+            ("comment", 5),
+            ("forceadd", 6),
+            ("skbinfo", 7),
+            ("bucketsize", 8),
+            ("initval", 8),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:net,port,net",
+        aliases: &[],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Net, DataKind::Port, DataKind::Net],
+        revisions: &[
+            ("forceadd", 1),
+            ("skbinfo", 2),
+            ("bucketsize", 3),
+            ("initval", 3),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "hash:net,iface",
+        aliases: &["netifacehash"],
+        method: SetMethod::Hash,
+        data_types: &[DataKind::Net, DataKind::Iface],
+        revisions: &[
+            ("nomatch", 1),
+            ("counters", 3),
+            ("comment", 4),
+            ("forceadd", 5),
+            ("skbinfo", 6),
+            ("wildcard", 7),
+            ("bucketsize", 8),
+            ("initval", 8),
+        ],
+    },
+    SetTypeDescriptor {
+        typename: "list:set",
+        aliases: &["setlist"],
+        method: SetMethod::List,
+        data_types: &[DataKind::Set],
+        revisions: &[("counters", 1), ("comment", 2), ("skbinfo", 3)],
+    },
+];
+
+impl SetTypeDescriptor {
+    /// Find the set type of `typename`, which is either the canonical typename or an alias.
+    pub fn parse(typename: &str) -> Result<&'static SetTypeDescriptor, Error> {
+        SET_TYPES
+            .iter()
+            .find(|desc| desc.typename == typename || desc.aliases.contains(&typename))
+            .ok_or_else(|| Error::UnknownType(typename.into()))
+    }
+
+    /// Find the set type of `T`.
+    pub fn of<T>() -> Result<&'static SetTypeDescriptor, Error>
+    where
+        T: SetType,
+        T::Method: TypeName,
+        T::DataType: TypeName,
+    {
+        Self::parse(&T::to_cstring().to_string_lossy())
+    }
+
+    /// Test if the set type stores any net data type, i.e. hash:*net*.
+    fn has_net(&self) -> bool {
+        self.data_types.contains(&DataKind::Net)
+    }
+
+    /// The options which are legal for the create command.
+    pub fn create_options(&self) -> Vec<&'static str> {
+        let mut options = vec!["timeout", "counters", "comment", "skbinfo"];
+        match self.method {
+            SetMethod::Bitmap => options.push("range"),
+            SetMethod::Hash => {
+                if self.data_types != [DataKind::Mac] {
+                    options.push("family");
+                }
+                options.extend(["hashsize", "maxelem", "bucketsize", "initval", "forceadd"]);
+            }
+            SetMethod::List => options.push("size"),
+        }
+        if self.data_types.contains(&DataKind::Mark) {
+            options.push("markmask");
+        }
+        if self.data_types == [DataKind::Ip] && self.method != SetMethod::List {
+            options.push("netmask");
+        }
+        if matches!(self.typename, "hash:ip" | "hash:ip,port" | "hash:net,net") {
+            if self.typename != "hash:ip" {
+                options.push("netmask");
+            }
+            options.push("bitmask");
+        }
+        options
+    }
+
+    /// The extensions which are legal for the entries of the set.
+    pub fn entry_extensions(&self) -> Vec<&'static str> {
+        let mut extensions = vec![
+            "timeout", "packets", "bytes", "comment", "skbmark", "skbprio", "skbqueue",
+        ];
+        if self.method == SetMethod::Hash && self.has_net() {
+            extensions.push("nomatch");
+        }
+        if self.data_types.contains(&DataKind::Iface) {
+            extensions.push("wildcard");
+        }
+        extensions
+    }
+
+    /// Check that the `options` of an entry are all extensions of the set type,
+    /// `Error::CAOption` is returned for the first one which is not.
+    pub fn check_extensions(&self, options: &[AddOption]) -> Result<(), Error> {
+        let extensions = self.entry_extensions();
+        match options
+            .iter()
+            .find(|option| !extensions.contains(&option.name()))
+        {
+            Some(option) => Err(Error::CAOption(format!(
+                "{} is not supported by {}",
+                option.name(),
+                self.typename
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the minimal revision of the set type which supports `option`.
+    pub fn revision(&self, option: &str) -> u8 {
+        self.revisions
+            .iter()
+            .find(|(name, _)| *name == option)
+            .map(|(_, revision)| *revision)
+            .unwrap_or(0)
+    }
+}
+
 /// Errors defined in this crate.
 #[derive(Debug, From, Display)]
 pub enum Error {
@@ -500,14 +1020,189 @@ pub enum Error {
     CAOption(String),
     #[from(ignore)]
     DataParse(String),
+    /// A set with the target name already exists.
+    #[from(ignore)]
+    SetExists(String),
+    /// The set is referenced by the kernel, e.g. by an iptables rule or a list:set.
+    #[from(ignore)]
+    SetInUse(String),
+    /// The set type does not match the expected one.
+    #[from(ignore)]
+    TypeMismatch(String),
+    /// A line of a restore failed, with the line number, the text of the line and the message.
+    #[from(ignore)]
+    #[display("RestoreLine:[{}, '{}', '{}']", line, text, message)]
+    RestoreLine {
+        line: usize,
+        text: String,
+        message: String,
+    },
+    Io(std::io::Error),
+    /// The typename is not a set type supported by ipset.
+    #[from(ignore)]
+    UnknownType(String),
+    /// The create option is not supported by the revision of the set type in the kernel.
+    #[from(ignore)]
+    #[display(
+        "Unsupported:['{}', {}, {}]",
+        option,
+        required_revision,
+        kernel_revision
+    )]
+    Unsupported {
+        option: String,
+        required_revision: u8,
+        kernel_revision: u8,
+    },
+}
+
+/// The code of a failed command, which is the errno of the kernel or an `IPSET_ERR_*` code of
+/// ipset. libipset only reports the message of the code, so the code is found by looking up the
+/// message in the messages libipset renders for all the known codes, see `Error::code`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ErrorCode {
+    /// The errno or `IPSET_ERR_*` code, 0 for the errors found by libipset itself.
+    pub errno: i32,
+    /// The command the message of the code is specific to, like `IPSET_CMD_ADD`, or 0.
+    pub cmd: u32,
+    /// The method of the set type for the codes from `IPSET_ERR_TYPE_SPECIFIC`, which have
+    /// a meaning for each method.
+    pub method: Option<SetMethod>,
+}
+
+/// The type specific codes of the hash types, from `linux/netfilter/ipset/ip_set_hash.h`.
+const IPSET_ERR_HASH_FULL: i32 = binding::ipset_errno_IPSET_ERR_TYPE_SPECIFIC as i32;
+const IPSET_ERR_HASH_ELEM: i32 = IPSET_ERR_HASH_FULL + 1;
+const IPSET_ERR_INVALID_PROTO: i32 = IPSET_ERR_HASH_FULL + 2;
+const IPSET_ERR_MISSING_PROTO: i32 = IPSET_ERR_HASH_FULL + 3;
+/// The type specific code of the bitmap types, from `linux/netfilter/ipset/ip_set_bitmap.h`.
+const IPSET_ERR_BITMAP_RANGE: i32 = binding::ipset_errno_IPSET_ERR_TYPE_SPECIFIC as i32;
+
+impl ErrorCode {
+    /// Get the kind of the code.
+    pub fn kind(&self) -> ErrorKind {
+        if self.errno < binding::ipset_errno_IPSET_ERR_PRIVATE as i32 {
+            return match self.errno {
+                libc::ENOENT => ErrorKind::SetNotFound,
+                libc::EEXIST => ErrorKind::SetExists,
+                libc::EPERM => ErrorKind::PermissionDenied,
+                libc::EBUSY => ErrorKind::Busy,
+                libc::ENOMEM => ErrorKind::NoMemory,
+                _ => ErrorKind::Other,
+            };
+        }
+        match (self.method, self.errno) {
+            (Some(SetMethod::Hash), IPSET_ERR_HASH_FULL) => return ErrorKind::HashFull,
+            (Some(SetMethod::Hash), IPSET_ERR_HASH_ELEM) => return ErrorKind::HashElem,
+            (Some(SetMethod::Hash), IPSET_ERR_INVALID_PROTO | IPSET_ERR_MISSING_PROTO) => {
+                return ErrorKind::InvalidProto
+            }
+            (Some(SetMethod::Bitmap), IPSET_ERR_BITMAP_RANGE) => return ErrorKind::BitmapRange,
+            _ => {}
+        }
+        match (self.errno as u32, self.cmd) {
+            (binding::ipset_errno_IPSET_ERR_EXIST_SETNAME2, binding::ipset_cmd_IPSET_CMD_SWAP) => {
+                ErrorKind::SecondSetNotFound
+            }
+            (binding::ipset_errno_IPSET_ERR_EXIST_SETNAME2, _) => ErrorKind::SetExists,
+            (
+                binding::ipset_errno_IPSET_ERR_BUSY | binding::ipset_errno_IPSET_ERR_REFERENCED,
+                _,
+            ) => ErrorKind::SetInUse,
+            (binding::ipset_errno_IPSET_ERR_TYPE_MISMATCH, _) => ErrorKind::TypeMismatch,
+            (binding::ipset_errno_IPSET_ERR_FIND_TYPE, _) => ErrorKind::TypeNotSupported,
+            (binding::ipset_errno_IPSET_ERR_MAX_SETS, _) => ErrorKind::MaxSets,
+            (binding::ipset_errno_IPSET_ERR_EXIST, binding::ipset_cmd_IPSET_CMD_ADD) => {
+                ErrorKind::AlreadyAdded
+            }
+            (binding::ipset_errno_IPSET_ERR_EXIST, binding::ipset_cmd_IPSET_CMD_DEL) => {
+                ErrorKind::NotAdded
+            }
+            (binding::ipset_errno_IPSET_ERR_EXIST, binding::ipset_cmd_IPSET_CMD_TEST) => {
+                ErrorKind::NotInSet
+            }
+            (binding::ipset_errno_IPSET_ERR_INVALID_CIDR, _) => ErrorKind::InvalidCidr,
+            (binding::ipset_errno_IPSET_ERR_INVALID_FAMILY, _) => ErrorKind::InvalidFamily,
+            (
+                binding::ipset_errno_IPSET_ERR_TIMEOUT
+                | binding::ipset_errno_IPSET_ERR_COUNTER
+                | binding::ipset_errno_IPSET_ERR_COMMENT
+                | binding::ipset_errno_IPSET_ERR_SKBINFO,
+                _,
+            ) => ErrorKind::ExtensionNotSupported,
+            (binding::ipset_errno_IPSET_ERR_PROTOCOL, _) => ErrorKind::Protocol,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Kind of the errors reported by libipset and the kernel, decided by the `ErrorCode`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// ENOENT, the set does not exist.
+    SetNotFound,
+    /// EEXIST or IPSET_ERR_EXIST_SETNAME2, a set with the name already exists.
+    SetExists,
+    /// IPSET_ERR_EXIST_SETNAME2 of swap, the second set does not exist.
+    SecondSetNotFound,
+    /// IPSET_ERR_BUSY or IPSET_ERR_REFERENCED, the set is in use.
+    SetInUse,
+    /// IPSET_ERR_TYPE_MISMATCH, the types of the sets do not match.
+    TypeMismatch,
+    /// IPSET_ERR_FIND_TYPE, the set type is not supported by the kernel.
+    TypeNotSupported,
+    /// IPSET_ERR_MAX_SETS, the maximal number of sets is reached.
+    MaxSets,
+    /// IPSET_ERR_EXIST of add, the element is already added.
+    AlreadyAdded,
+    /// IPSET_ERR_EXIST of del, the element is not added.
+    NotAdded,
+    /// IPSET_ERR_EXIST of test, the element is not in the set, reported as a warning.
+    NotInSet,
+    /// IPSET_ERR_HASH_FULL, the hash is full.
+    HashFull,
+    /// IPSET_ERR_HASH_ELEM, a null-valued element can not be stored in a hash.
+    HashElem,
+    /// IPSET_ERR_BITMAP_RANGE, the element is out of the range of the bitmap.
+    BitmapRange,
+    /// IPSET_ERR_INVALID_CIDR, the cidr is invalid.
+    InvalidCidr,
+    /// IPSET_ERR_INVALID_FAMILY, the family is not supported by the set type.
+    InvalidFamily,
+    /// IPSET_ERR_INVALID_PROTO or IPSET_ERR_MISSING_PROTO.
+    InvalidProto,
+    /// IPSET_ERR_TIMEOUT, IPSET_ERR_COUNTER, IPSET_ERR_COMMENT or IPSET_ERR_SKBINFO, the
+    /// extension is used but the set was created without its support.
+    ExtensionNotSupported,
+    /// IPSET_ERR_PROTOCOL, the protocol of the kernel and libipset do not match.
+    Protocol,
+    /// EPERM, CAP_NET_ADMIN is required.
+    PermissionDenied,
+    /// EBUSY, the kernel is busy.
+    Busy,
+    /// ENOMEM, the kernel is out of memory.
+    NoMemory,
+    /// All the other errors.
+    Other,
 }
 
 impl Error {
-    pub(crate) fn cmd_contains(&self, m: &str) -> bool {
-        if let Error::Cmd(message, _) = self {
-            message.contains(m)
-        } else {
-            false
+    /// Get the kind of the error, `ErrorKind::Other` for the errors not from libipset.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::SetExists(_) => ErrorKind::SetExists,
+            Error::SetInUse(_) => ErrorKind::SetInUse,
+            Error::TypeMismatch(_) => ErrorKind::TypeMismatch,
+            _ => self.code().map_or(ErrorKind::Other, |code| code.kind()),
+        }
+    }
+
+    /// Get the code of a failed command from its message, `None` for a message not rendered
+    /// by libipset for a code, like the errors found by libipset itself.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Cmd(message, _) | Error::TypeGet(message, _) => error_code(message),
+            _ => None,
         }
     }
 
@@ -523,6 +1218,33 @@ impl Error {
 
 impl StdError for Error {}
 
+/// Split the "Error in line N: " prefix added by libipset in restore mode from `message`.
+pub(crate) fn split_line_error(message: &str) -> Option<(usize, &str)> {
+    let (line, message) = message.strip_prefix("Error in line ")?.split_once(": ")?;
+    Some((line.parse().ok()?, message))
+}
+
+/// Split `line` into whitespace separated fields, a field starting with a quote lasts until the
+/// closing quote, like `comment "blocked by rule 42"` as printed by libipset. The quotes are kept
+/// in the field.
+pub(crate) fn split_fields(line: &str) -> Result<Vec<&str>, Error> {
+    let mut fields = vec![];
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let end = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| Error::InvalidOutput(format!("unterminated quote in {}", line)))?;
+            end + 2
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Ok(fields)
+}
+
 /// The bitmap:ip set type uses a memory range to store either IPv4 host (default) or IPv4 network addresses.
 /// A bitmap:ip type of set can store up to 65536 entries.
 #[derive(SetType)]
@@ -603,6 +1325,14 @@ pub struct HashNetIface;
 #[derive(SetType)]
 pub struct ListSet;
 
+/// A set type only known at runtime, used by `DynSession`.
+pub struct Dyn;
+
+impl SetType for Dyn {
+    type Method = ();
+    type DataType = DynEntry;
+}
+
 pub trait WithNetmask {}
 
 impl WithNetmask for BitmapMethod {}
@@ -612,12 +1342,22 @@ impl WithNetmask for HashMethod {}
 #[allow(unused_imports)]
 mod tests {
     use std::net::IpAddr;
+    use std::str::FromStr;
 
+    use crate::binding;
+    use crate::types::{
+        split_fields, split_line_error, AddOption, EntryExtensions, Error, ErrorCode, ErrorKind,
+        MAX_COMMENT_SIZE,
+    };
     use crate::types::{
         BitmapIp, BitmapIpMac, BitmapPort, HashIp, HashIpMac, HashIpMark, HashIpPort, HashIpPortIp,
         HashIpPortNet, HashMac, HashNet, HashNetIface, HashNetNet, HashNetPort, HashNetPortNet,
         ListSet,
     };
+    use crate::types::{
+        BitmapMethod, BitmapRange, DataKind, Dyn, DynEntry, Family, HashMethod, ListHeader,
+        ListMethod, NormalListResult, SetInfo, SetMethod, SetTypeDescriptor, WithHeader, SET_TYPES,
+    };
     use crate::types::{
         IfaceDataType, IpDataType, MacDataType, MarkDataType, NetDataType, Parse, PortDataType,
         SetDataType, ToCString,
@@ -665,6 +1405,9 @@ mod tests {
         assert_eq!("1235", format!("{}", port));
         port.parse("1234").unwrap();
         assert_eq!("1234", format!("{}", port));
+        port.parse("udp:53").unwrap();
+        assert_eq!(port.proto(), Some(17));
+        assert_eq!("udp:53", format!("{}", port));
     }
 
     #[test]
@@ -696,6 +1439,398 @@ mod tests {
         assert_eq!("192.168.3.2", format!("{}", data.2));
     }
 
+    #[test]
+    fn test_header() {
+        let header = ListHeader::from_str(
+            "family inet6 hashsize 1024 maxelem 65536 timeout 600 netmask 64 counters forceadd bucketsize 12 initval 0x4e5d7a2c",
+        )
+        .unwrap();
+        let header = HashMethod::header(header).unwrap();
+        assert_eq!(header.family, Some(Family::Inet6));
+        assert_eq!(header.hash_size, 1024);
+        assert_eq!(header.max_elem, 65536);
+        assert_eq!(header.timeout, Some(600));
+        assert_eq!(header.netmask, Some(64));
+        assert_eq!(header.bucket_size, Some(12));
+        assert_eq!(header.initval, Some(0x4e5d7a2c));
+        assert!(header.counters && header.forceadd && !header.comment);
+
+        let header =
+            ListHeader::from_str("range 192.168.0.0-192.168.0.255 netmask 24 comment").unwrap();
+        let header = BitmapMethod::header(header).unwrap();
+        assert_eq!(
+            header.range,
+            BitmapRange::Ip(
+                "192.168.0.0".parse().unwrap(),
+                "192.168.0.255".parse().unwrap()
+            )
+        );
+        assert_eq!(header.netmask, Some(24));
+        assert!(header.comment);
+
+        let header = ListHeader::from_str("range 0-1024").unwrap();
+        assert_eq!(header.range, Some(BitmapRange::Port(0, 1024)));
+
+        let header = ListHeader::from_str("size 8 timeout 10 skbinfo").unwrap();
+        let header = ListMethod::header(header).unwrap();
+        assert_eq!(header.size, 8);
+        assert_eq!(header.timeout, Some(10));
+        assert!(header.skbinfo);
+
+        assert!(HashMethod::header(ListHeader::from_str("family inet").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_header_error() {
+        let header =
+            ListHeader::from_str("family inet hashsize 1024 newflag bucketsize 12 newkey 0x10")
+                .unwrap();
+        assert_eq!(header.hash_size, Some(1024));
+        assert_eq!(header.bucket_size, Some(12));
+        assert_eq!(
+            header.extra,
+            vec![
+                ("newflag".to_string(), None),
+                ("newkey".to_string(), Some("0x10".to_string()))
+            ]
+        );
+        assert_eq!(
+            header.to_string(),
+            "family inet hashsize 1024 newflag bucketsize 12 newkey 0x10"
+        );
+        // the listed order is kept, the other fields follow in the order of libipset.
+        let mut header = ListHeader::from_str(
+            "family inet hashsize 1024 bitmask 255.255.0.0 maxelem 65536 bucketsize 12",
+        )
+        .unwrap();
+        assert_eq!(
+            header.to_string(),
+            "family inet hashsize 1024 bitmask 255.255.0.0 maxelem 65536 bucketsize 12"
+        );
+        header.timeout = Some(10);
+        header.counters = true;
+        assert_eq!(
+            header.to_string(),
+            "family inet hashsize 1024 bitmask 255.255.0.0 maxelem 65536 bucketsize 12 timeout 10 counters"
+        );
+        let header = ListHeader::from_str("newkey abc newflag counters").unwrap();
+        assert_eq!(
+            header.extra,
+            vec![
+                ("newkey".to_string(), Some("abc".to_string())),
+                ("newflag".to_string(), None)
+            ]
+        );
+        assert!(header.counters);
+        for s in [
+            "family",
+            "family inet8",
+            "hashsize",
+            "hashsize big",
+            "initval 12",
+            "range 5",
+        ] {
+            assert!(matches!(
+                ListHeader::from_str(s),
+                Err(Error::InvalidOutput(_))
+            ));
+        }
+
+        let mut result = NormalListResult::<HashIp>::default();
+        result
+            .update_with("Name: test", IpDataType::default)
+            .unwrap();
+        result
+            .update_with("Memory limit: 1024", IpDataType::default)
+            .unwrap();
+        assert_eq!(result.extra["Memory limit"], "1024");
+        result.update_with("Members:", IpDataType::default).unwrap();
+        result
+            .update_with(
+                "10.0.0.1 timeout 10 newext abc newflag comment \"a b\"",
+                IpDataType::default,
+            )
+            .unwrap();
+        result
+            .update_with("10.0.0.2 newext def", IpDataType::default)
+            .unwrap();
+        let items = result.items.as_ref().unwrap();
+        let (_, extensions) = &items[0];
+        assert_eq!(extensions.timeout, Some(10));
+        assert_eq!(extensions.comment.as_deref(), Some("a b"));
+        assert_eq!(
+            extensions.extra,
+            vec![
+                ("newext".to_string(), Some("abc".to_string())),
+                ("newflag".to_string(), None)
+            ]
+        );
+        // the unknown extensions are kept for each entry.
+        assert_eq!(
+            items[1].1.extra,
+            vec![("newext".to_string(), Some("def".to_string()))]
+        );
+        assert!(!result.extra.contains_key("newext"));
+        assert!(result.update_with("garbage", IpDataType::default).is_err());
+        assert!(result
+            .update_with("Header: hashsize", IpDataType::default)
+            .is_err());
+    }
+
+    #[test]
+    fn test_option_revision() {
+        let revision =
+            |typename, option| SetTypeDescriptor::parse(typename).unwrap().revision(option);
+        assert_eq!(revision("hash:ip", "bucketsize"), 5);
+        assert_eq!(revision("hash:net,port", "comment"), 5);
+        assert_eq!(revision("bitmap:port", "skbinfo"), 3);
+        assert_eq!(revision("hash:ip", "timeout"), 0);
+        assert_eq!(revision("hash:ip,port", "netmask"), 7);
+        assert_eq!(revision("hash:net,net", "netmask"), 4);
+        assert_eq!(revision("hash:net,iface", "wildcard"), 7);
+    }
+
+    #[test]
+    fn test_descriptor() {
+        let desc = SetTypeDescriptor::parse("nethash").unwrap();
+        assert_eq!(desc.typename, "hash:net");
+        assert_eq!(desc.method, SetMethod::Hash);
+        assert!(desc.entry_extensions().contains(&"nomatch"));
+        let desc = SetTypeDescriptor::of::<HashMac>().unwrap();
+        assert_eq!(desc.data_types, &[DataKind::Mac]);
+        assert!(!desc.create_options().contains(&"family"));
+        let desc = SetTypeDescriptor::of::<BitmapIp>().unwrap();
+        assert!(desc.create_options().contains(&"netmask"));
+        assert!(!desc.entry_extensions().contains(&"nomatch"));
+        assert!(SetTypeDescriptor::parse("hash:net,mac").is_err());
+        for typename in SET_TYPES.iter().map(|desc| desc.typename) {
+            assert!(DynEntry::template(typename).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_create_options() {
+        let hash = "family hashsize maxelem bucketsize initval forceadd";
+        let table = [
+            ("bitmap:ip", "range netmask".to_string()),
+            ("bitmap:ip,mac", "range".to_string()),
+            ("bitmap:port", "range".to_string()),
+            ("hash:ip", format!("{} netmask bitmask", hash)),
+            (
+                "hash:mac",
+                "hashsize maxelem bucketsize initval forceadd".to_string(),
+            ),
+            ("hash:ip,mac", hash.to_string()),
+            ("hash:ip,mark", format!("{} markmask", hash)),
+            ("hash:ip,port", format!("{} netmask bitmask", hash)),
+            ("hash:ip,port,ip", hash.to_string()),
+            ("hash:ip,port,net", hash.to_string()),
+            ("hash:net", hash.to_string()),
+            ("hash:net,net", format!("{} netmask bitmask", hash)),
+            ("hash:net,port", hash.to_string()),
+            ("hash:net,port,net", hash.to_string()),
+            ("hash:net,iface", hash.to_string()),
+            ("list:set", "size".to_string()),
+        ];
+        assert_eq!(table.len(), SET_TYPES.len());
+        for (typename, options) in table {
+            let mut expected: Vec<_> = "timeout counters comment skbinfo"
+                .split(' ')
+                .chain(options.split(' '))
+                .collect();
+            expected.sort_unstable();
+            let mut options = SetTypeDescriptor::parse(typename).unwrap().create_options();
+            options.sort_unstable();
+            assert_eq!(options, expected, "{}", typename);
+        }
+        assert_eq!(
+            SetTypeDescriptor::parse("hash:net,net")
+                .unwrap()
+                .revision("bitmask"),
+            4
+        );
+    }
+
+    #[test]
+    fn test_check_extensions() {
+        let desc = SetTypeDescriptor::parse("hash:ip").unwrap();
+        assert!(desc
+            .check_extensions(&[AddOption::Timeout(10), AddOption::Comment("a".into())])
+            .is_ok());
+        assert!(matches!(
+            desc.check_extensions(&[AddOption::Nomatch]),
+            Err(Error::CAOption(message)) if message == "nomatch is not supported by hash:ip"
+        ));
+        let desc = SetTypeDescriptor::parse("hash:net").unwrap();
+        assert!(desc.check_extensions(&[AddOption::Nomatch]).is_ok());
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let code = |errno: u32, cmd| ErrorCode {
+            errno: errno as i32,
+            cmd,
+            method: None,
+        };
+        let exist = binding::ipset_errno_IPSET_ERR_EXIST;
+        let add = code(exist, binding::ipset_cmd_IPSET_CMD_ADD);
+        assert_eq!(add.kind(), ErrorKind::AlreadyAdded);
+        let del = code(exist, binding::ipset_cmd_IPSET_CMD_DEL);
+        assert_eq!(del.kind(), ErrorKind::NotAdded);
+        let test = code(exist, binding::ipset_cmd_IPSET_CMD_TEST);
+        assert_eq!(test.kind(), ErrorKind::NotInSet);
+        assert_eq!(code(libc::ENOENT as u32, 0).kind(), ErrorKind::SetNotFound);
+        // A message not rendered by libipset has no code.
+        let err = Error::Cmd("Operation not permitted".into(), true);
+        assert_eq!(err.code(), None);
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(
+            code(libc::EPERM as u32, 0).kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(code(libc::EBUSY as u32, 0).kind(), ErrorKind::Busy);
+
+        let setname2 = binding::ipset_errno_IPSET_ERR_EXIST_SETNAME2;
+        let swap = code(setname2, binding::ipset_cmd_IPSET_CMD_SWAP);
+        assert_eq!(swap.kind(), ErrorKind::SecondSetNotFound);
+        let rename = code(setname2, binding::ipset_cmd_IPSET_CMD_RENAME);
+        assert_eq!(rename.kind(), ErrorKind::SetExists);
+        let protocol = code(binding::ipset_errno_IPSET_ERR_PROTOCOL, 0);
+        assert_eq!(protocol.kind(), ErrorKind::Protocol);
+
+        // The type specific codes depend on the method.
+        let mut full = code(binding::ipset_errno_IPSET_ERR_TYPE_SPECIFIC, 0);
+        assert_eq!(full.kind(), ErrorKind::Other);
+        full.method = Some(SetMethod::Hash);
+        assert_eq!(full.kind(), ErrorKind::HashFull);
+        full.method = Some(SetMethod::Bitmap);
+        assert_eq!(full.kind(), ErrorKind::BitmapRange);
+        assert_eq!(Error::InvalidOutput("".into()).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_split_line_error() {
+        assert_eq!(
+            split_line_error("Error in line 3: The set with the given name does not exist"),
+            Some((3, "The set with the given name does not exist"))
+        );
+        assert_eq!(
+            split_line_error("The set with the given name does not exist"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_set_info() {
+        let mut info = SetInfo::default();
+        for line in [
+            "Name: test",
+            "Type: hash:ip",
+            "Revision: 6",
+            "Header: family inet hashsize 1024 maxelem 65536 bucketsize 12 initval 0x7a3c2b1d",
+            "Size in memory: 216",
+            "References: 1",
+            "Number of entries: 3",
+        ] {
+            info.update_from_str(line).unwrap();
+        }
+        assert_eq!(info.name, "test");
+        assert_eq!(info.typ, "hash:ip");
+        assert_eq!(info.family(), Some(Family::Inet));
+        assert_eq!(info.references, 1);
+        assert_eq!(info.entry_size, 3);
+        assert!(info.open::<HashIp>().is_ok());
+        assert!(info.open::<HashNet>().is_err());
+    }
+
+    #[test]
+    fn test_dyn_entry() {
+        let mut entry = DynEntry::template("hash:net,port").unwrap();
+        entry.parse("192.168.0.0/24,80").unwrap();
+        assert_eq!(entry.to_string(), "192.168.0.0/24,80");
+        assert!(entry.same_type(&DynEntry::template("hash:net,port").unwrap()));
+        assert!(!entry.same_type(&DynEntry::template("hash:ip,port").unwrap()));
+        assert!(entry.parse("192.168.0.0/24").is_err());
+        assert!(DynEntry::default().parse("192.168.0.1").is_err());
+        assert!(DynEntry::template("hash:foo").is_err());
+
+        let mut result = NormalListResult::<Dyn>::default();
+        let template = DynEntry::template("hash:ip").unwrap();
+        result.update_with("Members:", || template.clone()).unwrap();
+        result
+            .update_with("10.0.0.1 timeout 10", || template.clone())
+            .unwrap();
+        let items = result.items.unwrap();
+        assert_eq!(items[0].0.to_string(), "10.0.0.1");
+        assert_eq!(items[0].1.timeout, Some(10));
+    }
+
+    #[test]
+    fn test_split_fields() {
+        assert_eq!(
+            split_fields(" 10.0.0.1 timeout 10  comment \"blocked by IDS rule 42\" nomatch")
+                .unwrap(),
+            vec![
+                "10.0.0.1",
+                "timeout",
+                "10",
+                "comment",
+                "\"blocked by IDS rule 42\"",
+                "nomatch"
+            ]
+        );
+        assert_eq!(
+            split_fields("comment \"\"").unwrap(),
+            vec!["comment", "\"\""]
+        );
+        assert!(split_fields("comment \"blocked").is_err());
+
+        let options =
+            AddOption::parse_all(&split_fields("comment \"a b\" timeout 1").unwrap()).unwrap();
+        assert!(matches!(options[0], AddOption::Comment(ref c) if c == "a b"));
+        assert!(options[0].validate().is_ok());
+        let options = AddOption::parse_all(&["wildcard", "timeout", "1"]).unwrap();
+        assert!(matches!(
+            options[..],
+            [AddOption::Wildcard, AddOption::Timeout(1)]
+        ));
+        assert!(AddOption::Comment("a \"b\"".into()).validate().is_err());
+        assert!(AddOption::Comment("a".repeat(MAX_COMMENT_SIZE))
+            .validate()
+            .is_ok());
+        assert!(AddOption::Comment("a".repeat(MAX_COMMENT_SIZE + 1))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_entry_extensions() {
+        let mut result = NormalListResult::<HashNet>::default();
+        result
+            .update_with("Members:", NetDataType::default)
+            .unwrap();
+        let line = "10.0.0.0/8 timeout 10 packets 2 bytes 120 comment \"lan hosts\" skbmark 0x10 skbprio 1:2 skbqueue 3 nomatch";
+        result.update_with(line, NetDataType::default).unwrap();
+        result
+            .update_with("192.168.0.0/16", NetDataType::default)
+            .unwrap();
+        let items = result.items.unwrap();
+        let extensions = &items[0].1;
+        assert_eq!(extensions.timeout, Some(10));
+        assert_eq!(extensions.packets, Some(2));
+        assert_eq!(extensions.bytes, Some(120));
+        assert_eq!(extensions.comment.as_deref(), Some("lan hosts"));
+        assert_eq!(extensions.skbmark, Some((0x10, u32::MAX)));
+        assert_eq!(extensions.skbprio, Some((1, 2)));
+        assert_eq!(extensions.skbqueue, Some(3));
+        assert!(extensions.nomatch);
+        assert_eq!(items[1].1, EntryExtensions::default());
+
+        let options: Vec<_> = extensions.options().iter().map(|o| o.to_string()).collect();
+        assert_eq!(options.join(" "), &line[11..]);
+        assert_eq!(&EntryExtensions::from(extensions.options()), extensions);
+    }
+
     #[test]
     fn test_type_name() {
         assert_eq!(HashIp::to_cstring().to_str().unwrap(), "hash:ip");
@@ -729,7 +1864,22 @@ mod tests {
     }
 }
 
+/// Protocol versions as `(min, max)` supported by libipset and the kernel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub library: (u8, u8),
+    pub kernel: (u8, u8),
+}
+
+/// Revisions of a set type supported by the kernel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TypeRevision {
+    pub min: u8,
+    pub max: u8,
+}
+
 /// Options which ipset supported
+#[derive(Clone, Copy)]
 pub enum EnvOption {
     /// Sorted output. When listing or saving sets, the entries are listed sorted.
     Sorted,
@@ -760,7 +1910,9 @@ impl EnvOption {
 }
 
 /// Options for creation and addition.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum AddOption {
     /// The value of the timeout parameter for the create command means the default timeout value
     /// (in seconds) for new entries. If a set is created with timeout support, then the same
@@ -783,7 +1935,8 @@ pub enum AddOption {
     /// enables you to annotate an ipset entry  with  an  arbitrary  string. This  string is
     /// completely ignored by both the kernel and ipset itself and is purely for providing a
     /// convenient means to document the reason for an entry's existence. Comments must not contain
-    /// any quotation marks and the usual escape character (\) has no meaning
+    /// any quotation marks and the usual escape character (\) has no meaning, and must not be
+    /// longer than `MAX_COMMENT_SIZE` bytes.
     Comment(String),
     /// The  hash  set  types which can store net type of data (i.e. hash:*net*) support the
     /// optional nomatch option when adding entries. When matching elements in the set, entries
@@ -793,8 +1946,159 @@ pub enum AddOption {
     /// If one wants to test the existence of an element marked with nomatch in a set,
     /// then the flag must be specified too.
     Nomatch,
+    /// The hash:net,iface set type supports the wildcard flag when adding entries. If the flag is
+    /// set, then prefix matching is used when comparing with the interface name of the entry.
+    Wildcard,
 }
 
+/// The max length of a comment in bytes, `IPSET_MAX_COMMENT_SIZE` of the kernel.
+pub const MAX_COMMENT_SIZE: usize = 255;
+
+impl AddOption {
+    /// Check the option before it's sent to the kernel, a comment must not contain quotes and
+    /// must not be longer than `MAX_COMMENT_SIZE`.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        match self {
+            AddOption::Comment(comment) if comment.contains('"') => Err(Error::CAOption(format!(
+                "comment {} contains quotation marks",
+                comment
+            ))),
+            AddOption::Comment(comment) if comment.len() > MAX_COMMENT_SIZE => Err(
+                Error::CAOption(format!("comment is longer than {} bytes", MAX_COMMENT_SIZE)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse the options from `fields` of an entry in the `ipset add` format.
+    pub(crate) fn parse_all(fields: &[&str]) -> Result<Vec<AddOption>, Error> {
+        let value = |i: usize| {
+            fields
+                .get(i + 1)
+                .copied()
+                .ok_or_else(|| Error::InvalidOutput(fields.join(" ")))
+        };
+        let hex = |s: &str| {
+            s.strip_prefix("0x")
+                .ok_or_else(|| Error::InvalidOutput(s.into()))
+                .and_then(|s| Ok(u32::from_str_radix(s, 16)?))
+        };
+        let mut i = 0;
+        let mut options = vec![];
+        while i < fields.len() {
+            match fields[i] {
+                "timeout" => {
+                    options.push(AddOption::Timeout(value(i)?.parse()?));
+                }
+                "packets" => {
+                    options.push(AddOption::Packets(value(i)?.parse()?));
+                }
+                "bytes" => {
+                    options.push(AddOption::Bytes(
+                        value(i)?.trim().replace("\0", "").parse()?,
+                    ));
+                }
+                "comment" => {
+                    let comment = value(i)?;
+                    let comment = comment
+                        .strip_prefix('"')
+                        .and_then(|comment| comment.strip_suffix('"'))
+                        .unwrap_or(comment);
+                    options.push(AddOption::Comment(comment.to_string()));
+                }
+                "skbmark" => {
+                    let (mark, mask) = match value(i)?.split_once('/') {
+                        Some((mark, mask)) => (hex(mark)?, hex(mask)?),
+                        None => (hex(value(i)?)?, u32::MAX),
+                    };
+                    options.push(AddOption::SkbMark(mark, mask));
+                }
+                "skbprio" => {
+                    let (major, minor) = value(i)?
+                        .split_once(':')
+                        .ok_or_else(|| Error::InvalidOutput(fields[i + 1].into()))?;
+                    options.push(AddOption::SkbPrio(
+                        u16::from_str_radix(major, 16)?,
+                        u16::from_str_radix(minor, 16)?,
+                    ));
+                }
+                "skbqueue" => {
+                    options.push(AddOption::SkbQueue(value(i)?.parse()?));
+                }
+                "nomatch" => {
+                    options.push(AddOption::Nomatch);
+                    i += 1;
+                    continue;
+                }
+                "wildcard" => {
+                    options.push(AddOption::Wildcard);
+                    i += 1;
+                    continue;
+                }
+                _ => {
+                    return Err(Error::InvalidOutput(fields[i].into()));
+                }
+            }
+            i += 2
+        }
+        Ok(options)
+    }
+
+    /// The name of the option in the `ipset add` command.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            AddOption::Timeout(_) => "timeout",
+            AddOption::Bytes(_) => "bytes",
+            AddOption::Packets(_) => "packets",
+            AddOption::SkbMark(_, _) => "skbmark",
+            AddOption::SkbPrio(_, _) => "skbprio",
+            AddOption::SkbQueue(_) => "skbqueue",
+            AddOption::Comment(_) => "comment",
+            AddOption::Nomatch => "nomatch",
+            AddOption::Wildcard => "wildcard",
+        }
+    }
+}
+
+/// A failed line of `IPSet::restore_with_report`.
+#[derive(Debug)]
+pub struct RestoreFailure {
+    /// The line number, starting from 1.
+    pub line: usize,
+    /// The text of the line.
+    pub text: String,
+    pub error: Error,
+}
+
+/// Report of `IPSet::restore_with_report`.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// The numbers of the applied lines, the empty and comment lines are not included.
+    pub applied: Vec<usize>,
+    pub failures: Vec<RestoreFailure>,
+}
+
+/// Outcome of an entry in the batched commands, like `Session::add_many`.
+#[derive(Debug)]
+pub enum EntryOutcome {
+    /// The entry is added or deleted.
+    Applied,
+    /// The entry is already added, or not added when deleting.
+    Unchanged,
+    /// The entry is rejected with the error.
+    Failed(Error),
+}
+
+/// Result of listing a set, the members are serialized in the ipset notation with the
+/// `serde` feature.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "T::DataType: crate::serialize::Notation",
+        deserialize = ""
+    ))
+)]
 pub struct NormalListResult<T: SetType> {
     pub name: String,
     pub typ: String,
@@ -803,9 +2107,21 @@ pub struct NormalListResult<T: SetType> {
     pub size_in_memory: u32,
     pub references: u32,
     pub entry_size: u32,
-    pub items: Option<Vec<(T::DataType, Option<Vec<AddOption>>)>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialize::items"))]
+    pub items: Option<Vec<(T::DataType, EntryExtensions)>>,
+    /// The lines unknown to this crate before the members, like the ones added by newer libipset.
+    pub extra: BTreeMap<String, String>,
 }
 
+/// The extensions of the members printed by libipset.
+pub(crate) const MEMBER_EXTENSIONS: &[&str] = &[
+    "timeout", "packets", "bytes", "comment", "skbmark", "skbprio", "skbqueue", "nomatch",
+    "wildcard",
+];
+
+/// The extensions of the members without a value.
+const MEMBER_FLAGS: &[&str] = &["nomatch", "wildcard"];
+
 impl<T: SetType> Default for NormalListResult<T> {
     fn default() -> Self {
         Self {
@@ -817,169 +2133,618 @@ impl<T: SetType> Default for NormalListResult<T> {
             references: 0,
             entry_size: 0,
             items: None,
+            extra: Default::default(),
         }
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum ListResult<T: SetType> {
     Normal(NormalListResult<T>),
     Terse(Vec<String>),
 }
 
 impl<T: SetType> NormalListResult<T> {
-    pub(crate) fn update_from_str(&mut self, line: &str) -> Result<(), Error> {
+    /// Update the result with a line of the list output, the members are parsed into the data
+    /// created by `template`.
+    pub(crate) fn update_with<F>(&mut self, line: &str, template: F) -> Result<(), Error>
+    where
+        F: Fn() -> T::DataType,
+    {
         if self.items.is_none() {
-            let fields: Vec<_> = line.splitn(2, ":").collect();
-            match fields[0] {
-                "Name" => {
-                    self.name = fields[1].trim().to_string();
-                }
-                "Type" => {
-                    self.typ = fields[1].trim().to_string();
-                }
-                "Revision" => {
-                    self.revision = fields[1].trim().parse()?;
-                }
-                "Header" => {
-                    self.header = ListHeader::from_str(fields[1].trim());
-                }
-                "Size in memory" => {
-                    self.size_in_memory = fields[1].trim().parse()?;
-                }
-                "References" => {
-                    self.references = fields[1].trim().parse()?;
-                }
-                "Number of entries" => {
-                    self.entry_size = fields[1].trim().parse()?;
-                }
-                "Members" => {
-                    self.items = Some(Vec::new());
-                }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| Error::InvalidOutput(line.into()))?;
+            let value = value.trim();
+            match key {
+                "Name" => self.name = value.to_string(),
+                "Type" => self.typ = value.to_string(),
+                "Revision" => self.revision = value.parse()?,
+                "Header" => self.header = value.parse()?,
+                "Size in memory" => self.size_in_memory = value.parse()?,
+                "References" => self.references = value.parse()?,
+                "Number of entries" => self.entry_size = value.parse()?,
+                "Members" => self.items = Some(Vec::new()),
                 _ => {
-                    unreachable!("unexpected {}", fields[0])
+                    self.extra.insert(key.to_string(), value.to_string());
                 }
             }
         } else {
-            let fields: Vec<_> = line.split_ascii_whitespace().collect();
-            let mut data = T::DataType::default();
-            let mut add_options = None;
-            if fields.len() == 0 || data.parse(fields[0]).is_err() {
-                return Err(Error::InvalidOutput(String::from(line)));
-            } else if fields.len() > 1 {
-                let mut i = 1;
-                let mut options = vec![];
-                while i < fields.len() {
-                    match fields[i] {
-                        "timeout" => {
-                            options.push(AddOption::Timeout(fields[i + 1].parse()?));
-                        }
-                        "packets" => {
-                            options.push(AddOption::Packets(fields[i + 1].parse()?));
-                        }
-                        "bytes" => {
-                            options.push(AddOption::Bytes(fields[i + 1].trim().replace("\0", "").parse()?));
-                        }
-                        "comment" => {
-                            options.push(AddOption::Comment(fields[i + 1].to_string()));
-                        }
-                        "skbmark" => {
-                            let values: Vec<_> = fields[i + 1].split('/').collect();
-                            let v0 =
-                                u32::from_str_radix(values[0].strip_prefix("0x").unwrap(), 16)?;
-                            let v1 = if values.len() > 1 {
-                                u32::from_str_radix(values[1].strip_prefix("0x").unwrap(), 16)?
-                            } else {
-                                u32::MAX
-                            };
-                            options.push(AddOption::SkbMark(v0, v1));
-                        }
-                        "skbprio" => {
-                            let values: Vec<_> = fields[i + 1].split(':').collect();
-                            let v0 = u16::from_str_radix(values[0], 16)?;
-                            let v1 = u16::from_str_radix(values[1], 16)?;
-                            options.push(AddOption::SkbPrio(v0, v1));
-                        }
-                        "skbqueue" => {
-                            options.push(AddOption::SkbQueue(fields[i + 1].parse()?));
-                        }
-                        "nomatch" => {
-                            options.push(AddOption::Nomatch);
-                            i += 1;
-                            continue;
-                        }
-                        _ => {
-                            unreachable!("{} not supported", fields[i]);
-                        }
-                    }
-                    i += 2
-                }
-                add_options = Some(options);
+            let member = self.parse_member(line, template)?;
+            if let Some(items) = &mut self.items {
+                items.push(member);
             }
-            self.items.as_mut().unwrap().push((data, add_options));
         }
         Ok(())
     }
+
+    /// Parse a member line of the list output into the data created by `template`. The unknown
+    /// extensions are kept in `EntryExtensions::extra`, an unknown one is taken as a flag if the
+    /// next word is a known extension.
+    pub(crate) fn parse_member<F>(
+        &self,
+        line: &str,
+        template: F,
+    ) -> Result<(T::DataType, EntryExtensions), Error>
+    where
+        F: Fn() -> T::DataType,
+    {
+        let fields = split_fields(line)?;
+        let mut data = template();
+        if fields.is_empty() || data.parse(fields[0]).is_err() {
+            return Err(Error::InvalidOutput(String::from(line)));
+        }
+        let mut known = vec![];
+        let mut extra = vec![];
+        let mut i = 1;
+        while i < fields.len() {
+            let key = fields[i];
+            let value = fields.get(i + 1).copied().filter(|value| {
+                !MEMBER_FLAGS.contains(&key)
+                    && (MEMBER_EXTENSIONS.contains(&key) || !MEMBER_EXTENSIONS.contains(value))
+            });
+            if MEMBER_EXTENSIONS.contains(&key) {
+                known.push(key);
+                known.extend(value);
+            } else {
+                extra.push((key.to_string(), value.map(String::from)));
+            }
+            i += if value.is_some() { 2 } else { 1 };
+        }
+        let mut extensions: EntryExtensions = AddOption::parse_all(&known)?.into();
+        extensions.extra = extra;
+        Ok((data, extensions))
+    }
+}
+
+impl Display for AddOption {
+    /// Format the option as in the `ipset save` format.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddOption::Timeout(timeout) => write!(f, "timeout {}", timeout),
+            AddOption::Bytes(bytes) => write!(f, "bytes {}", bytes),
+            AddOption::Packets(packets) => write!(f, "packets {}", packets),
+            AddOption::SkbMark(mark, u32::MAX) => write!(f, "skbmark 0x{:x}", mark),
+            AddOption::SkbMark(mark, mask) => write!(f, "skbmark 0x{:x}/0x{:x}", mark, mask),
+            AddOption::SkbPrio(major, minor) => write!(f, "skbprio {:x}:{:x}", major, minor),
+            AddOption::SkbQueue(queue) => write!(f, "skbqueue {}", queue),
+            AddOption::Comment(comment) => write!(f, "comment \"{}\"", comment),
+            AddOption::Nomatch => write!(f, "nomatch"),
+            AddOption::Wildcard => write!(f, "wildcard"),
+        }
+    }
+}
+
+/// Extensions of an entry as typed fields, the unset fields are not listed for the entry.
+/// The listed entry can be added again with the same extensions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntryExtensions {
+    pub timeout: Option<u32>,
+    pub packets: Option<u64>,
+    pub bytes: Option<u64>,
+    pub comment: Option<String>,
+    /// Mark and mask.
+    pub skbmark: Option<(u32, u32)>,
+    /// Major and minor of the tc class.
+    pub skbprio: Option<(u16, u16)>,
+    pub skbqueue: Option<u16>,
+    pub nomatch: bool,
+    pub wildcard: bool,
+    /// The extensions unknown to this crate in the listed order, with the value if any.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extra: Vec<(String, Option<String>)>,
+}
+
+impl EntryExtensions {
+    /// The extensions as options, in the order of the `ipset save` format.
+    pub fn options(&self) -> Vec<AddOption> {
+        let mut options = vec![];
+        if let Some(timeout) = self.timeout {
+            options.push(AddOption::Timeout(timeout));
+        }
+        if let Some(packets) = self.packets {
+            options.push(AddOption::Packets(packets));
+        }
+        if let Some(bytes) = self.bytes {
+            options.push(AddOption::Bytes(bytes));
+        }
+        if let Some(comment) = &self.comment {
+            options.push(AddOption::Comment(comment.clone()));
+        }
+        if let Some((mark, mask)) = self.skbmark {
+            options.push(AddOption::SkbMark(mark, mask));
+        }
+        if let Some((major, minor)) = self.skbprio {
+            options.push(AddOption::SkbPrio(major, minor));
+        }
+        if let Some(queue) = self.skbqueue {
+            options.push(AddOption::SkbQueue(queue));
+        }
+        if self.nomatch {
+            options.push(AddOption::Nomatch);
+        }
+        if self.wildcard {
+            options.push(AddOption::Wildcard);
+        }
+        options
+    }
 }
 
+impl FromIterator<AddOption> for EntryExtensions {
+    fn from_iter<I: IntoIterator<Item = AddOption>>(options: I) -> Self {
+        let mut extensions = EntryExtensions::default();
+        for option in options {
+            match option {
+                AddOption::Timeout(timeout) => extensions.timeout = Some(timeout),
+                AddOption::Bytes(bytes) => extensions.bytes = Some(bytes),
+                AddOption::Packets(packets) => extensions.packets = Some(packets),
+                AddOption::SkbMark(mark, mask) => extensions.skbmark = Some((mark, mask)),
+                AddOption::SkbPrio(major, minor) => extensions.skbprio = Some((major, minor)),
+                AddOption::SkbQueue(queue) => extensions.skbqueue = Some(queue),
+                AddOption::Comment(comment) => extensions.comment = Some(comment),
+                AddOption::Nomatch => extensions.nomatch = true,
+                AddOption::Wildcard => extensions.wildcard = true,
+            }
+        }
+        extensions
+    }
+}
+
+impl From<Vec<AddOption>> for EntryExtensions {
+    fn from(options: Vec<AddOption>) -> Self {
+        options.into_iter().collect()
+    }
+}
+
+/// Options of an entry accepted by `Session::add`, a slice of `AddOption` or `EntryExtensions`.
+pub trait AddOptions {
+    fn add_options(&self) -> Vec<AddOption>;
+}
+
+impl AddOptions for [AddOption] {
+    fn add_options(&self) -> Vec<AddOption> {
+        self.to_vec()
+    }
+}
+
+impl<const N: usize> AddOptions for [AddOption; N] {
+    fn add_options(&self) -> Vec<AddOption> {
+        self.to_vec()
+    }
+}
+
+impl AddOptions for Vec<AddOption> {
+    fn add_options(&self) -> Vec<AddOption> {
+        self.clone()
+    }
+}
+
+impl AddOptions for EntryExtensions {
+    fn add_options(&self) -> Vec<AddOption> {
+        self.options()
+    }
+}
+
+/// Summary of a set in the host, see `IPSet::sets`.
 #[derive(Default, Debug)]
+pub struct SetInfo {
+    pub name: String,
+    pub typ: String,
+    pub revision: u32,
+    pub header: ListHeader,
+    pub size_in_memory: u32,
+    pub references: u32,
+    pub entry_size: u32,
+    /// The lines unknown to this crate, see `NormalListResult::extra`.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl SetInfo {
+    /// Protocol family of the set, `None` for the types without family like `hash:mac`.
+    pub fn family(&self) -> Option<Family> {
+        self.header.family
+    }
+
+    /// Open a session for the set, `Error::TypeMismatch` is returned if the set is not of type `T`.
+    pub fn open<T>(&self) -> Result<Session<T>, Error>
+    where
+        T: SetType,
+        T::Method: TypeName,
+        T::DataType: TypeName,
+    {
+        if self.typ.as_bytes() == T::to_cstring().as_bytes() {
+            Ok(Session::new(self.name.clone()))
+        } else {
+            Err(Error::TypeMismatch(self.typ.clone()))
+        }
+    }
+
+    /// Open a session for the set with the type checked at runtime.
+    pub fn open_dyn(&self) -> Result<DynSession, Error> {
+        DynSession::with_typename(self.name.clone(), self.typ.clone())
+    }
+
+    pub(crate) fn update_from_str(&mut self, line: &str) -> Result<(), Error> {
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::InvalidOutput(line.into()))?;
+        let value = value.trim();
+        match key {
+            "Name" => self.name = value.to_string(),
+            "Type" => self.typ = value.to_string(),
+            "Revision" => self.revision = value.parse()?,
+            "Header" => self.header = value.parse()?,
+            "Size in memory" => self.size_in_memory = value.parse()?,
+            "References" => self.references = value.parse()?,
+            "Number of entries" => self.entry_size = value.parse()?,
+            "Members" => {}
+            _ => {
+                self.extra.insert(key.to_string(), value.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Protocol family of a set.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Family {
+    Inet,
+    Inet6,
+}
+
+impl Display for Family {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Family::Inet => write!(f, "inet"),
+            Family::Inet6 => write!(f, "inet6"),
+        }
+    }
+}
+
+/// Range of a bitmap set, `bitmap:ip` and `bitmap:ip,mac` use ip range, `bitmap:port` uses port range.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum BitmapRange {
+    Ip(IpAddr, IpAddr),
+    Port(u16, u16),
+}
+
+impl Parse for BitmapRange {
+    fn parse(&mut self, s: &str) -> Result<(), Error> {
+        let (from, to) = s
+            .split_once('-')
+            .ok_or_else(|| Error::InvalidOutput(s.into()))?;
+        *self = if let (Ok(from), Ok(to)) = (from.parse(), to.parse()) {
+            BitmapRange::Port(from, to)
+        } else {
+            BitmapRange::Ip(from.parse()?, to.parse()?)
+        };
+        Ok(())
+    }
+}
+
+impl Display for BitmapRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitmapRange::Ip(from, to) => write!(f, "{}-{}", from, to),
+            BitmapRange::Port(from, to) => write!(f, "{}-{}", from, to),
+        }
+    }
+}
+
+/// Header line of a set as listed by libipset, all the fields are optional as they depend on the set type.
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ListHeader {
-    ipv6: bool,
-    hash_size: u32,
-    bucket_size: Option<u32>,
-    max_elem: u32,
-    counters: bool,
-    comment: bool,
-    skbinfo: bool,
-    initval: Option<u32>
+    pub family: Option<Family>,
+    pub range: Option<BitmapRange>,
+    pub hash_size: Option<u32>,
+    pub bucket_size: Option<u32>,
+    pub max_elem: Option<u32>,
+    pub size: Option<u32>,
+    pub timeout: Option<u32>,
+    pub netmask: Option<u8>,
+    pub markmask: Option<u32>,
+    pub counters: bool,
+    pub comment: bool,
+    pub skbinfo: bool,
+    pub forceadd: bool,
+    pub initval: Option<u32>,
+    /// The keys unknown to this crate, like the ones added by newer kernels, with their values
+    /// in the listed order.
+    pub extra: Vec<(String, Option<String>)>,
+    /// The keys in the listed order, which is kept by `Display`. Empty for a header built in code.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub order: Vec<String>,
 }
 
-impl ListHeader {
-    pub fn from_str(s: &str) -> Self {
+/// The flags of the header printed by libipset, the other keys are followed by a value.
+const HEADER_FLAGS: &[&str] = &["counters", "comment", "skbinfo", "forceadd"];
+
+/// The keys of the header printed by libipset with a value.
+const HEADER_KEYS: &[&str] = &[
+    "family",
+    "range",
+    "hashsize",
+    "bucketsize",
+    "maxelem",
+    "size",
+    "timeout",
+    "netmask",
+    "bitmask",
+    "markmask",
+    "initval",
+];
+
+impl FromStr for ListHeader {
+    type Err = Error;
+
+    /// Parse the header, `Error::InvalidOutput` is returned for malformed values.
+    /// The unknown keys are kept in `extra`, an unknown key is taken as a flag if the next word
+    /// is a key printed by libipset.
+    fn from_str(s: &str) -> Result<Self, Error> {
         let s: Vec<_> = s.split_whitespace().collect();
         let mut header = ListHeader::default();
         let mut i = 0;
         while i < s.len() {
-            match s[i] {
-                "family" => {
-                    header.ipv6 = s[i + 1] == "inet6";
-                    i += 2;
-                }
-                "hashsize" => {
-                    header.hash_size = s[i + 1].parse().unwrap();
-                    i += 2;
-                }
-                "bucketsize" => {
-                    header.bucket_size = Some(s[i + 1].parse().unwrap());
-                    i += 2;
-                },
-                "maxelem" => {
-                    header.max_elem = s[i + 1].parse().unwrap();
-                    i += 2;
-                }
-                "counters" => {
-                    header.counters = true;
-                    i += 1;
-                }
-                "comment" => {
-                    header.comment = true;
-                    i += 1;
-                }
-                "skbinfo" => {
-                    header.skbinfo = true;
-                    i += 1;
-                }
-                "initval" => {
-                    if let Some(initval) = s[i + 1].strip_prefix("0x") {
-                        header.initval = Some(u32::from_str_radix(initval, 16).unwrap());
-                    }
-                    i += 2;
+            let key = s[i];
+            let value = s.get(i + 1).copied().filter(|value| {
+                if HEADER_FLAGS.contains(&key) {
+                    false
+                } else {
+                    HEADER_KEYS.contains(&key)
+                        || !(HEADER_FLAGS.contains(value) || HEADER_KEYS.contains(value))
                 }
+            });
+            header.set_field(key, value)?;
+            i += if value.is_some() { 2 } else { 1 };
+        }
+        Ok(header)
+    }
+}
 
-                _ => {
-                    unreachable!("{} not supported", s[i]);
-                }
+impl ListHeader {
+    /// Set the field `key` of the header, `value` is `None` for the flags like `counters`.
+    /// The unknown keys are kept in `extra`.
+    pub(crate) fn set_field(&mut self, key: &str, value: Option<&str>) -> Result<(), Error> {
+        let invalid = || Error::InvalidOutput(format!("{} {}", key, value.unwrap_or("")));
+        let hex = || {
+            value
+                .and_then(|value| value.strip_prefix("0x"))
+                .and_then(|value| u32::from_str_radix(value, 16).ok())
+                .ok_or_else(invalid)
+        };
+        let number = || {
+            value
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(invalid)
+        };
+        self.order.push(key.to_string());
+        match key {
+            "counters" => self.counters = true,
+            "comment" => self.comment = true,
+            "skbinfo" => self.skbinfo = true,
+            "forceadd" => self.forceadd = true,
+            "family" => {
+                self.family = match value {
+                    Some("inet") => Some(Family::Inet),
+                    Some("inet6") => Some(Family::Inet6),
+                    _ => return Err(invalid()),
+                };
+            }
+            "range" => {
+                let mut range = BitmapRange::Port(0, 0);
+                range
+                    .parse(value.ok_or_else(invalid)?)
+                    .map_err(|_| invalid())?;
+                self.range = Some(range);
+            }
+            "hashsize" => self.hash_size = Some(number()?),
+            "bucketsize" => self.bucket_size = Some(number()?),
+            "maxelem" => self.max_elem = Some(number()?),
+            "size" => self.size = Some(number()?),
+            "timeout" => self.timeout = Some(number()?),
+            "netmask" => {
+                self.netmask = Some(
+                    value
+                        .and_then(|value| value.parse().ok())
+                        .ok_or_else(invalid)?,
+                );
+            }
+            "markmask" => self.markmask = Some(hex()?),
+            "initval" => self.initval = Some(hex()?),
+            _ => self
+                .extra
+                .push((key.to_string(), value.map(|value| value.to_string()))),
+        }
+        Ok(())
+    }
+}
+
+impl Display for ListHeader {
+    /// Format the header in the listed order of the keys, see `order`. The keys not listed are
+    /// in the order printed by libipset, which is the order of the create arguments of the set
+    /// types, after the listed ones.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut items = vec![];
+        if let Some(family) = self.family {
+            items.push(("family", format!("family {}", family)));
+        }
+        if let Some(markmask) = self.markmask {
+            items.push(("markmask", format!("markmask 0x{:08x}", markmask)));
+        }
+        if let Some(range) = self.range {
+            items.push(("range", format!("range {}", range)));
+        }
+        if let Some(hash_size) = self.hash_size {
+            items.push(("hashsize", format!("hashsize {}", hash_size)));
+        }
+        if let Some(max_elem) = self.max_elem {
+            items.push(("maxelem", format!("maxelem {}", max_elem)));
+        }
+        if let Some(netmask) = self.netmask {
+            items.push(("netmask", format!("netmask {}", netmask)));
+        }
+        if let Some(size) = self.size {
+            items.push(("size", format!("size {}", size)));
+        }
+        if let Some(timeout) = self.timeout {
+            items.push(("timeout", format!("timeout {}", timeout)));
+        }
+        for (flag, name) in [
+            (self.counters, "counters"),
+            (self.comment, "comment"),
+            (self.forceadd, "forceadd"),
+            (self.skbinfo, "skbinfo"),
+        ] {
+            if flag {
+                items.push((name, name.to_string()));
+            }
+        }
+        if let Some(bucket_size) = self.bucket_size {
+            items.push(("bucketsize", format!("bucketsize {}", bucket_size)));
+        }
+        if let Some(initval) = self.initval {
+            items.push(("initval", format!("initval 0x{:08x}", initval)));
+        }
+        for (key, value) in &self.extra {
+            match value {
+                Some(value) => items.push((key, format!("{} {}", key, value))),
+                None => items.push((key, key.clone())),
             }
         }
-        header
+        // the sort is stable, so the repeated and the not listed keys keep their order.
+        items.sort_by_key(|(key, _)| {
+            self.order
+                .iter()
+                .position(|listed| listed == key)
+                .unwrap_or(usize::MAX)
+        });
+        let items: Vec<_> = items.into_iter().map(|(_, item)| item).collect();
+        write!(f, "{}", items.join(" "))
+    }
+}
+
+/// Header of a hash set.
+#[derive(Debug)]
+pub struct HashHeader {
+    /// `None` for `hash:mac`, which has no family.
+    pub family: Option<Family>,
+    pub hash_size: u32,
+    pub max_elem: u32,
+    pub bucket_size: Option<u32>,
+    pub initval: Option<u32>,
+    pub netmask: Option<u8>,
+    pub markmask: Option<u32>,
+    pub timeout: Option<u32>,
+    pub counters: bool,
+    pub comment: bool,
+    pub skbinfo: bool,
+    pub forceadd: bool,
+}
+
+/// Header of a bitmap set.
+#[derive(Debug)]
+pub struct BitmapHeader {
+    pub range: BitmapRange,
+    pub netmask: Option<u8>,
+    pub timeout: Option<u32>,
+    pub counters: bool,
+    pub comment: bool,
+    pub skbinfo: bool,
+}
+
+/// Header of a list set.
+#[derive(Debug)]
+pub struct ListSetHeader {
+    pub size: u32,
+    pub timeout: Option<u32>,
+    pub counters: bool,
+    pub comment: bool,
+    pub skbinfo: bool,
+}
+
+/// A trait to get the typed header for a ipset method from the listed header.
+pub trait WithHeader {
+    type Header;
+    fn header(header: ListHeader) -> Result<Self::Header, Error>;
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T, Error> {
+    value.ok_or_else(|| Error::InvalidOutput(format!("{} not found in header", name)))
+}
+
+impl WithHeader for HashMethod {
+    type Header = HashHeader;
+
+    fn header(header: ListHeader) -> Result<Self::Header, Error> {
+        Ok(HashHeader {
+            family: header.family,
+            hash_size: required(header.hash_size, "hashsize")?,
+            max_elem: required(header.max_elem, "maxelem")?,
+            bucket_size: header.bucket_size,
+            initval: header.initval,
+            netmask: header.netmask,
+            markmask: header.markmask,
+            timeout: header.timeout,
+            counters: header.counters,
+            comment: header.comment,
+            skbinfo: header.skbinfo,
+            forceadd: header.forceadd,
+        })
+    }
+}
+
+impl WithHeader for BitmapMethod {
+    type Header = BitmapHeader;
+
+    fn header(header: ListHeader) -> Result<Self::Header, Error> {
+        Ok(BitmapHeader {
+            range: required(header.range, "range")?,
+            netmask: header.netmask,
+            timeout: header.timeout,
+            counters: header.counters,
+            comment: header.comment,
+            skbinfo: header.skbinfo,
+        })
+    }
+}
+
+impl WithHeader for ListMethod {
+    type Header = ListSetHeader;
+
+    fn header(header: ListHeader) -> Result<Self::Header, Error> {
+        Ok(ListSetHeader {
+            size: required(header.size, "size")?,
+            timeout: header.timeout,
+            counters: header.counters,
+            comment: header.comment,
+            skbinfo: header.skbinfo,
+        })
     }
 }
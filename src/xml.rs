@@ -0,0 +1,379 @@
+//! Parser for the list output of libipset in the xml mode, see `Session::list_xml`.
+//! Only the subset of xml printed by libipset is supported: elements, attributes, text and
+//! the predefined entities.
+
+use crate::types::{
+    AddOption, EntryExtensions, Error, ListResult, NormalListResult, Parse, SetType,
+    MEMBER_EXTENSIONS,
+};
+
+/// An element of the xml document, the text of the children is not included in `text`.
+#[derive(Debug, Default)]
+pub(crate) struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    /// Parse the root element of `s`, the declaration and comments are skipped.
+    pub fn parse(s: &str) -> Result<Element, Error> {
+        let mut parser = Parser { s, pos: 0 };
+        parser.skip_misc();
+        let element = parser.element()?;
+        parser.skip_misc();
+        if parser.pos < s.len() {
+            return Err(parser.error("content after the root element"));
+        }
+        Ok(element)
+    }
+
+    /// Get the value of attribute `name`.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The text of the element, `None` for an empty element like `<nomatch/>`.
+    fn value(&self) -> Option<&str> {
+        if self.text.is_empty() {
+            None
+        } else {
+            Some(self.text.as_str())
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::InvalidOutput(format!("xml {} at {}", message, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.s.len() - self.rest().trim_start().len();
+    }
+
+    /// Skip the whitespace, declarations like `<?xml ...?>` and comments.
+    fn skip_misc(&mut self) {
+        loop {
+            self.skip_whitespace();
+            let end = if self.rest().starts_with("<?") {
+                self.rest().find("?>").map(|end| end + 2)
+            } else if self.rest().starts_with("<!--") {
+                self.rest().find("-->").map(|end| end + 3)
+            } else {
+                None
+            };
+            match end {
+                Some(end) => self.pos += end,
+                None => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", token)))
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, Error> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn element(&mut self) -> Result<Element, Error> {
+        self.expect("<")?;
+        let mut element = Element {
+            name: self.name()?.to_string(),
+            ..Default::default()
+        };
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            } else if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let key = self.name()?.to_string();
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.rest().starts_with('\'') {
+                "'"
+            } else {
+                "\""
+            };
+            self.expect(quote)?;
+            let len = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| self.error("unterminated attribute"))?;
+            let value = unescape(&self.rest()[..len])?;
+            self.pos += len + 1;
+            element.attributes.push((key, value));
+        }
+        loop {
+            let len = self.rest().find('<').unwrap_or(self.rest().len());
+            element.text.push_str(&unescape(&self.rest()[..len])?);
+            self.pos += len;
+            if self.rest().is_empty() {
+                return Err(self.error(&format!("unterminated element {}", element.name)));
+            } else if self.rest().starts_with("</") {
+                self.pos += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(self.error(&format!("mismatched end of {}", element.name)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                break;
+            } else if self.rest().starts_with("<!--") || self.rest().starts_with("<?") {
+                self.skip_misc();
+            } else {
+                element.children.push(self.element()?);
+            }
+        }
+        // the whitespace around the children is only for indentation.
+        if !element.children.is_empty() || element.text.trim().is_empty() {
+            element.text = element.text.trim().to_string();
+        }
+        Ok(element)
+    }
+}
+
+/// Replace the predefined and numeric entities in `s`.
+fn unescape(s: &str) -> Result<String, Error> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| Error::InvalidOutput(format!("xml unterminated entity in {}", s)))?;
+        let entity = &rest[start + 1..start + end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|n| n.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        result.push(c.ok_or_else(|| Error::InvalidOutput(format!("xml entity &{};", entity)))?);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parse the list `output` of libipset in the xml mode, the members are parsed into the data
+/// created by `template`. A `ListResult::Terse` is returned if the sets have no content, which
+/// is the output with `EnvOption::ListSetName`.
+pub(crate) fn parse_list<T, F>(output: &str, template: F) -> Result<ListResult<T>, Error>
+where
+    T: SetType,
+    F: Fn() -> T::DataType,
+{
+    let root = Element::parse(output)?;
+    let sets: Vec<_> = if root.name == "ipsets" {
+        root.children
+            .iter()
+            .filter(|set| set.name == "ipset")
+            .collect()
+    } else {
+        vec![&root]
+    };
+    if sets.iter().all(|set| set.children.is_empty()) {
+        return sets
+            .iter()
+            .map(|set| set_name(set).map(|name| name.to_string()))
+            .collect::<Result<_, _>>()
+            .map(ListResult::Terse);
+    }
+    match sets.as_slice() {
+        [set] => parse_set(set, template).map(ListResult::Normal),
+        _ => Err(Error::InvalidOutput(format!(
+            "xml {} sets listed",
+            sets.len()
+        ))),
+    }
+}
+
+fn set_name(set: &Element) -> Result<&str, Error> {
+    set.attribute("name")
+        .ok_or_else(|| Error::InvalidOutput("xml ipset without name".into()))
+}
+
+/// Parse the `ipset` element of a set.
+fn parse_set<T, F>(set: &Element, template: F) -> Result<NormalListResult<T>, Error>
+where
+    T: SetType,
+    F: Fn() -> T::DataType,
+{
+    let mut result = NormalListResult::<T> {
+        name: set_name(set)?.to_string(),
+        ..Default::default()
+    };
+    for child in &set.children {
+        match child.name.as_str() {
+            "type" => result.typ = child.text.clone(),
+            "revision" => result.revision = child.text.parse()?,
+            "header" => {
+                for field in &child.children {
+                    match field.name.as_str() {
+                        "memsize" => result.size_in_memory = field.text.parse()?,
+                        "references" => result.references = field.text.parse()?,
+                        "numentries" => result.entry_size = field.text.parse()?,
+                        key => result.header.set_field(key, field.value())?,
+                    }
+                }
+            }
+            "members" => {
+                let mut items = vec![];
+                for member in &child.children {
+                    items.push(parse_member::<T, _>(member, &template)?);
+                }
+                result.items = Some(items);
+            }
+            _ => {
+                result.extra.insert(child.name.clone(), child.text.clone());
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a `member` element, the `elem` child is the data and the others are the extensions.
+/// The unknown extensions are kept in `EntryExtensions::extra`.
+fn parse_member<T, F>(
+    member: &Element,
+    template: F,
+) -> Result<(T::DataType, EntryExtensions), Error>
+where
+    T: SetType,
+    F: Fn() -> T::DataType,
+{
+    let mut data = None;
+    let mut options = vec![];
+    let mut extra = vec![];
+    for child in &member.children {
+        if child.name == "elem" {
+            let mut elem = template();
+            elem.parse(&child.text)?;
+            data = Some(elem);
+        } else if !MEMBER_EXTENSIONS.contains(&child.name.as_str()) {
+            extra.push((child.name.clone(), child.value().map(String::from)));
+        } else {
+            let fields: Vec<_> = std::iter::once(child.name.as_str())
+                .chain(child.value())
+                .collect();
+            options.extend(AddOption::parse_all(&fields)?);
+        }
+    }
+    let data = data.ok_or_else(|| Error::InvalidOutput("xml member without elem".into()))?;
+    let mut extensions: EntryExtensions = options.into();
+    extensions.extra = extra;
+    Ok((data, extensions))
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::types::{Family, HashIp, IpDataType, ListResult};
+    use crate::xml::{parse_list, unescape, Element};
+
+    #[test]
+    fn test_element() {
+        let root = Element::parse(
+            "<?xml version=\"1.0\"?>\n<a x=\"1 &amp; 2\" y='3'>\n  <b>t&lt;&#x41;&#66;</b>\n  <c/>\n</a>\n",
+        )
+        .unwrap();
+        assert_eq!(root.name, "a");
+        assert_eq!(root.attribute("x"), Some("1 & 2"));
+        assert_eq!(root.attribute("y"), Some("3"));
+        assert_eq!(root.children[0].text, "t<AB");
+        assert_eq!(root.children[1].name, "c");
+        assert!(root.children[1].value().is_none());
+
+        assert!(Element::parse("<a><b></a>").is_err());
+        assert!(Element::parse("<a>").is_err());
+        assert!(Element::parse("<a/><b/>").is_err());
+        assert!(unescape("&foo;").is_err());
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let output = "<ipsets>\n\
+            <ipset name=\"test\">\n\
+            <type>hash:ip</type>\n\
+            <revision>6</revision>\n\
+            <header><family>inet</family><hashsize>1024</hashsize><maxelem>65536</maxelem>\
+            <timeout>600</timeout><comment/><bucketsize>12</bucketsize><initval>0x1d2c3b4a</initval>\
+            <memsize>216</memsize><references>1</references><numentries>2</numentries></header>\n\
+            <members>\n\
+            <member><elem>10.0.0.1</elem><timeout>10</timeout><comment>a &lt;b&gt; &amp; spaced</comment></member>\n\
+            <member><elem>10.0.0.2</elem><timeout>20</timeout><newext>5</newext></member>\n\
+            </members>\n\
+            </ipset>\n\
+            </ipsets>\n";
+        let result = match parse_list::<HashIp, _>(output, IpDataType::default).unwrap() {
+            ListResult::Normal(result) => result,
+            ListResult::Terse(_) => unreachable!(),
+        };
+        assert_eq!(result.name, "test");
+        assert_eq!(result.typ, "hash:ip");
+        assert_eq!(result.revision, 6);
+        assert_eq!(result.header.family, Some(Family::Inet));
+        assert_eq!(result.header.timeout, Some(600));
+        assert!(result.header.comment);
+        assert_eq!(result.header.initval, Some(0x1d2c3b4a));
+        assert_eq!(result.size_in_memory, 216);
+        assert_eq!(result.references, 1);
+        assert_eq!(result.entry_size, 2);
+        let items = result.items.unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].0.to_string(), "10.0.0.1");
+        assert_eq!(items[0].1.timeout, Some(10));
+        assert_eq!(items[0].1.comment.as_deref(), Some("a <b> & spaced"));
+        assert!(items[0].1.extra.is_empty());
+        assert_eq!(items[1].1.extra, vec![("newext".into(), Some("5".into()))]);
+        assert!(!result.extra.contains_key("newext"));
+
+        let output = "<ipsets>\n<ipset name=\"a\"/>\n<ipset name=\"b\"/>\n</ipsets>\n";
+        assert!(matches!(
+            parse_list::<HashIp, _>(output, IpDataType::default).unwrap(),
+            ListResult::Terse(names) if names == ["a", "b"]
+        ));
+        let output =
+            "<ipset name=\"a\"><members><member><timeout>1</timeout></member></members></ipset>";
+        assert!(parse_list::<HashIp, _>(output, IpDataType::default).is_err());
+    }
+}
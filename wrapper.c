@@ -4,33 +4,65 @@
 #include <stdint.h>
 #include <libipset/ipset.h>
 
-extern void ipset_out(void *p, const char *output, uint32_t len, uint32_t cap);
+extern int ipset_out(void *p, const char *output, uint32_t len);
 
 int print_out(struct ipset_session *session, void *p, const char *fmt, ...) {
     (void) session;
+    char buffer[1024];
+    char *data = buffer;
     va_list args;
-    int length = 1024;
-    int running = 1;
-    do {
-        char *data = malloc(length);
+    va_start(args, fmt);
+    int n = vsnprintf(buffer, sizeof(buffer), fmt, args);
+    va_end(args);
+    if (n < 0) {
+        return n;
+    }
+    if ((size_t) n >= sizeof(buffer)) {
+        data = malloc(n + 1);
         if (data == NULL) {
-            return 0;
+            return -1;
         }
         va_start(args, fmt);
-        int n = vsnprintf(data, length, fmt, args);
+        n = vsnprintf(data, n + 1, fmt, args);
         va_end(args);
-        if(n<0) {
-            free(data);
-            return n;
-        }
-        else if (n <= length) {
-            ipset_out(p, data, n, length);
-            running = 0;
-            length = n;
-        } else {
-            length = n;
-            free(data);
-        }
-    } while (running);
-    return length;
-}
\ No newline at end of file
+    }
+    if (n >= 0 && ipset_out(p, data, n) < 0) {
+        n = -1;
+    }
+    if (data != buffer) {
+        free(data);
+    }
+    return n;
+}
+
+int custom_error(struct ipset *ipset, void *p, int status, const char *msg, ...) {
+    (void) p;
+    struct ipset_session *session = ipset_session(ipset);
+    if (status && msg && ipset_session_report_type(session) != IPSET_ERROR) {
+        char data[1024];
+        va_list args;
+        va_start(args, msg);
+        vsnprintf(data, sizeof(data), msg, args);
+        va_end(args);
+        ipset_session_report(session, IPSET_ERROR, "%s", data);
+    }
+    return status ? -1 : 0;
+}
+
+int standard_error(struct ipset *ipset, void *p) {
+    (void) ipset;
+    (void) p;
+    return -1;
+}
+
+/* The name of the set type saved in the session by the last command, or NULL. */
+const char *saved_typename(struct ipset_session *session) {
+    const struct ipset_type *type = ipset_saved_type(session);
+    return type ? type->name : NULL;
+}
+
+/* The revision of the set type saved in the session by the last command, or -1. */
+int saved_revision(struct ipset_session *session) {
+    const struct ipset_type *type = ipset_saved_type(session);
+    return type ? type->revision : -1;
+}
@@ -1,3 +1,12 @@
 #include <libipset/ipset.h>
+#include <libipset/errcode.h>
 
-extern int print_out(struct ipset_session *session, void *p, const char *fmt, ...);
\ No newline at end of file
+extern int print_out(struct ipset_session *session, void *p, const char *fmt, ...);
+
+extern int custom_error(struct ipset *ipset, void *p, int status, const char *msg, ...);
+
+extern int standard_error(struct ipset *ipset, void *p);
+
+extern const char *saved_typename(struct ipset_session *session);
+
+extern int saved_revision(struct ipset_session *session);